@@ -1,8 +1,8 @@
 use mdns::Error;
 use std::time::Duration;
 
-const SERVICE_NAME: &'static str = "_http._tcp.local";
-const HOSTS: [&'static str; 2] = ["server1._http._tcp.local", "server2._http._tcp.local"];
+const SERVICE_NAME: &str = "_http._tcp.local";
+const HOSTS: [&str; 2] = ["server1._http._tcp.local", "server2._http._tcp.local"];
 
 #[async_std::main]
 async fn main() -> Result<(), Error> {
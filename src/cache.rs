@@ -0,0 +1,412 @@
+use crate::{Clock, Record, RecordKind, Response, ServiceInstance, SystemClock};
+
+use std::cmp::Reverse;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The fractions of a record's TTL after which we ask for it again, until it
+/// is received anew.
+///
+/// See [RFC 6762 §5.2](https://tools.ietf.org/html/rfc6762#section-5.2).
+const REFRESH_FRACTIONS: [f64; 4] = [0.80, 0.85, 0.90, 0.95];
+
+/// How recently a record must have been received to survive a cache-flush
+/// record of the same name and type, so that records split across several
+/// packets don't flush each other.
+///
+/// See [RFC 6762 §10.2](https://tools.ietf.org/html/rfc6762#section-10.2).
+const CACHE_FLUSH_GRACE: Duration = Duration::from_secs(1);
+
+/// A cache of records learned from mDNS responses.
+///
+/// Records are kept until their TTL lapses. The cache also keeps track of
+/// which records are nearing expiry so that they can be re-queried before
+/// they disappear.
+#[derive(Clone, Debug)]
+pub struct DiscoveryCache {
+    entries: Vec<CacheEntry>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    record: Record,
+    received_at: Instant,
+    /// How many refresh queries have been sent for this record.
+    refreshes_sent: usize,
+}
+
+impl CacheEntry {
+    fn lifetime(&self) -> Duration {
+        Duration::from_secs(self.record.ttl.into())
+    }
+
+    fn expires_at(&self) -> Instant {
+        self.received_at + self.lifetime()
+    }
+
+    /// Gets when the next refresh query for this record is due, if any are
+    /// left to send.
+    fn refresh_at(&self) -> Option<Instant> {
+        let fraction = REFRESH_FRACTIONS.get(self.refreshes_sent)?;
+        Some(self.received_at + self.lifetime().mul_f64(*fraction))
+    }
+
+    fn is_refresh_due(&self, now: Instant) -> bool {
+        self.refresh_at()
+            .is_some_and(|refresh_at| refresh_at <= now)
+    }
+
+    fn is_same_record(&self, record: &Record) -> bool {
+        self.record.name == record.name
+            && self.record.class == record.class
+            && self.record.kind == record.kind
+    }
+
+    fn is_same_set(&self, record: &Record) -> bool {
+        self.record.name == record.name
+            && self.record.class == record.class
+            && self.record.kind.query_type() == record.kind.query_type()
+    }
+}
+
+impl Default for DiscoveryCache {
+    fn default() -> Self {
+        DiscoveryCache::with_clock(SystemClock)
+    }
+}
+
+impl DiscoveryCache {
+    /// Creates a new, empty cache.
+    pub fn new() -> Self {
+        DiscoveryCache::default()
+    }
+
+    /// Creates a new, empty cache that reads the time from `clock`.
+    ///
+    /// This lets tests control when records expire and become due for a
+    /// refresh, using a [`ManualClock`](crate::ManualClock).
+    pub fn with_clock<C>(clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        DiscoveryCache {
+            entries: Vec::new(),
+            clock: Arc::new(clock),
+        }
+    }
+
+    /// Adds every record in a response to the cache.
+    ///
+    /// Records that are already cached have their TTL refreshed. A record
+    /// with a TTL of zero is a goodbye and removes the cached copy.
+    ///
+    /// A record with the cache-flush bit set replaces the cached records of
+    /// the same name and type with different data, such as the old address
+    /// of a device that changed IP. Without the bit, they are kept alongside.
+    pub fn insert(&mut self, response: &Response) {
+        let now = self.clock.now();
+        let flush_before = now.checked_sub(CACHE_FLUSH_GRACE);
+
+        for record in response.records() {
+            self.entries.retain(|entry| {
+                let flushed = record.cache_flush
+                    && entry.is_same_set(record)
+                    && flush_before.is_some_and(|before| entry.received_at <= before);
+
+                !flushed && !entry.is_same_record(record)
+            });
+
+            if record.ttl > 0 {
+                self.entries.push(CacheEntry {
+                    record: record.clone(),
+                    received_at: now,
+                    refreshes_sent: 0,
+                });
+            }
+        }
+    }
+
+    /// Gets an iterator over all records currently in the cache.
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.entries.iter().map(|entry| &entry.record)
+    }
+
+    /// Gets when a record of `name` was last received, if any are cached.
+    pub fn last_seen(&self, name: &str) -> Option<Instant> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.record.name == name)
+            .map(|entry| entry.received_at)
+            .max()
+    }
+
+    /// Gets every cached service instance, most recently seen first.
+    ///
+    /// An instance is cached if its `SRV` record is. It was last seen when
+    /// any of its own records were last received.
+    pub fn instances_by_recency(&self) -> Vec<ServiceInstance> {
+        let response = Response {
+            answers: self.records().cloned().collect(),
+            nameservers: Vec::new(),
+            additional: Vec::new(),
+            rtt: None,
+            local_addr: None,
+            interface_index: None,
+            peer_addr: None,
+        };
+
+        let mut instances: Vec<(Instant, ServiceInstance)> = Vec::new();
+
+        for record in self.records() {
+            if !matches!(record.kind, RecordKind::SRV { .. })
+                || instances
+                    .iter()
+                    .any(|(_, instance)| instance.name == record.name)
+            {
+                continue;
+            }
+
+            if let (Some(last_seen), Some(instance)) = (
+                self.last_seen(&record.name),
+                ServiceInstance::from_response(&record.name, &response),
+            ) {
+                instances.push((last_seen, instance));
+            }
+        }
+
+        instances.sort_by_key(|&(last_seen, _)| Reverse(last_seen));
+        instances
+            .into_iter()
+            .map(|(_, instance)| instance)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Gets the cached records answering a question, with their TTLs reduced
+    /// by the time they have spent in the cache.
+    pub(crate) fn answers(&self, name: &str, query_type: dns_parser::QueryType) -> Vec<Record> {
+        let now = self.clock.now();
+
+        self.entries
+            .iter()
+            .filter(|entry| entry.record.answers(name, query_type))
+            .filter_map(|entry| {
+                let remaining = entry.expires_at().checked_duration_since(now)?.as_secs();
+
+                if remaining == 0 {
+                    return None;
+                }
+
+                Some(Record {
+                    ttl: remaining as u32,
+                    ..entry.record.clone()
+                })
+            })
+            .collect()
+    }
+
+    /// Gets the cached records answering a question that can be listed as
+    /// known answers in a query.
+    ///
+    /// Records past half their TTL are left out, so that responders refresh
+    /// them. See [RFC 6762 §7.1](https://tools.ietf.org/html/rfc6762#section-7.1).
+    pub(crate) fn known_answers(
+        &self,
+        name: &str,
+        query_type: dns_parser::QueryType,
+    ) -> Vec<Record> {
+        let now = self.clock.now();
+
+        self.entries
+            .iter()
+            .filter(|entry| entry.record.answers(name, query_type))
+            .filter_map(|entry| {
+                let remaining = entry.expires_at().checked_duration_since(now)?;
+
+                if remaining < entry.lifetime() / 2 {
+                    return None;
+                }
+
+                Some(Record {
+                    ttl: remaining.as_secs() as u32,
+                    ..entry.record.clone()
+                })
+            })
+            .collect()
+    }
+
+    /// Removes every record of `name`.
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|entry| entry.record.name != name);
+    }
+
+    /// Removes all records whose TTL has lapsed.
+    pub fn expire(&mut self) {
+        let now = self.clock.now();
+        self.entries.retain(|entry| entry.expires_at() > now);
+    }
+
+    /// Gets the instant at which the next record should be re-queried.
+    pub fn next_refresh(&self) -> Option<Instant> {
+        self.entries.iter().filter_map(CacheEntry::refresh_at).min()
+    }
+
+    /// Gets how long until a record is due for a refresh or expires, whichever
+    /// comes first.
+    pub(crate) fn until_next_event(&self) -> Option<Duration> {
+        let next_expiry = self.entries.iter().map(CacheEntry::expires_at).min();
+        let next = match (self.next_refresh(), next_expiry) {
+            (Some(refresh), Some(expiry)) => refresh.min(expiry),
+            (next, None) | (None, next) => next?,
+        };
+
+        Some(next.saturating_duration_since(self.clock.now()))
+    }
+
+    /// Gets the questions to ask for all records that are due for a refresh
+    /// query.
+    ///
+    /// Each record is asked for at 80%, 85%, 90% and 95% of its TTL; receiving
+    /// the record again starts over.
+    pub fn take_due_refreshes(&mut self) -> Vec<(String, dns_parser::QueryType)> {
+        let now = self.clock.now();
+        let mut questions: Vec<(String, dns_parser::QueryType)> = Vec::new();
+
+        for entry in self.entries.iter_mut() {
+            if !entry.is_refresh_due(now) {
+                continue;
+            }
+
+            // Only one query is sent however many refreshes were missed.
+            while entry.is_refresh_due(now) {
+                entry.refreshes_sent += 1;
+            }
+
+            if let Some(query_type) = entry.record.kind.query_type() {
+                let question = (entry.record.name.clone(), query_type);
+                if !questions.contains(&question) {
+                    questions.push(question);
+                }
+            }
+        }
+
+        questions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, Section};
+
+    fn response(ttl: u32) -> Response {
+        Response {
+            answers: vec![Record {
+                name: "printer.local".to_owned(),
+                class: dns_parser::Class::IN,
+                ttl,
+                kind: RecordKind::A([192, 168, 1, 20].into()),
+                cache_flush: true,
+                section: Section::Answer,
+            }],
+            nameservers: Vec::new(),
+            additional: Vec::new(),
+            rtt: None,
+            local_addr: None,
+            interface_index: None,
+            peer_addr: None,
+        }
+    }
+
+    fn a_question() -> Vec<(String, dns_parser::QueryType)> {
+        vec![("printer.local".to_owned(), dns_parser::QueryType::A)]
+    }
+
+    #[test]
+    fn records_expire_after_their_ttl() {
+        let clock = ManualClock::new();
+        let mut cache = DiscoveryCache::with_clock(clock.clone());
+        cache.insert(&response(100));
+
+        clock.advance(Duration::from_secs(99));
+        cache.expire();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            cache.answers("printer.local", dns_parser::QueryType::A)[0].ttl,
+            1
+        );
+
+        clock.advance(Duration::from_secs(1));
+        cache.expire();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn goodbye_removes_record() {
+        let mut cache = DiscoveryCache::new();
+        cache.insert(&response(100));
+        cache.insert(&response(0));
+
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn refreshes_are_due_at_80_85_90_and_95_percent() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut cache = DiscoveryCache::with_clock(clock.clone());
+        cache.insert(&response(100));
+
+        assert_eq!(cache.next_refresh(), Some(start + Duration::from_secs(80)));
+
+        clock.advance(Duration::from_secs(79));
+        assert!(cache.take_due_refreshes().is_empty());
+
+        for percent in [80, 85, 90, 95].iter() {
+            let due = start + Duration::from_secs(*percent);
+            assert_eq!(cache.next_refresh(), Some(due));
+
+            clock.advance(due - clock.now());
+            assert_eq!(cache.take_due_refreshes(), a_question());
+            assert!(cache.take_due_refreshes().is_empty());
+        }
+
+        assert_eq!(cache.next_refresh(), None);
+    }
+
+    #[test]
+    fn missed_refreshes_are_sent_once() {
+        let clock = ManualClock::new();
+        let mut cache = DiscoveryCache::with_clock(clock.clone());
+        cache.insert(&response(100));
+
+        clock.advance(Duration::from_secs(92));
+        assert_eq!(cache.take_due_refreshes(), a_question());
+        assert!(cache.take_due_refreshes().is_empty());
+        assert_eq!(cache.until_next_event(), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn receiving_a_record_again_restarts_its_refreshes() {
+        let clock = ManualClock::new();
+        let mut cache = DiscoveryCache::with_clock(clock.clone());
+        cache.insert(&response(100));
+
+        clock.advance(Duration::from_secs(80));
+        assert_eq!(cache.take_due_refreshes(), a_question());
+
+        cache.insert(&response(100));
+        assert_eq!(
+            cache.next_refresh(),
+            Some(clock.now() + Duration::from_secs(80))
+        );
+    }
+}
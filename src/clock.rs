@@ -0,0 +1,92 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A source of the current time.
+///
+/// Everything that ages with time, such as the records in a
+/// [`DiscoveryCache`](crate::DiscoveryCache), reads the time from a clock so
+/// that tests can control it.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Gets the current time.
+    fn now(&self) -> Instant;
+}
+
+/// The real clock, which reads the system's monotonic time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, for testing.
+///
+/// Clones share the same time, so one can be handed to a cache while
+/// another is used to advance it.
+///
+/// ```rust
+/// use mdns::{DiscoveryCache, ManualClock, Record, RecordKind, Response, Section};
+/// use std::time::Duration;
+///
+/// let clock = ManualClock::new();
+/// let mut cache = DiscoveryCache::with_clock(clock.clone());
+///
+/// let record = Record {
+///     name: "printer.local".to_owned(),
+///     class: dns_parser::Class::IN,
+///     ttl: 120,
+///     kind: RecordKind::A([192, 168, 1, 20].into()),
+///     cache_flush: true,
+///     section: Section::Answer,
+/// };
+/// cache.insert(&Response {
+///     answers: vec![record],
+///     nameservers: Vec::new(),
+///     additional: Vec::new(),
+///     rtt: None,
+///     local_addr: None,
+///     interface_index: None,
+///     peer_addr: None,
+/// });
+///
+/// clock.advance(Duration::from_secs(119));
+/// cache.expire();
+/// assert_eq!(cache.len(), 1);
+///
+/// clock.advance(Duration::from_secs(1));
+/// cache.expire();
+/// assert!(cache.is_empty());
+/// ```
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// Creates a clock stopped at the current time.
+    pub fn new() -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Moves the clock forwards.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,52 @@
+use crate::{Error, RecordKind};
+
+use async_std::net::TcpStream;
+use futures_util::{pin_mut, StreamExt};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Discovers a service and connects to the first instance that accepts.
+///
+/// Each responding instance's addresses are tried in turn. Instances that
+/// respond without their addresses are resolved first, with whatever is left
+/// of `timeout`; those that fail to resolve are skipped. Fails with
+/// `Error::Timeout` if nothing could be connected to within `timeout`.
+pub async fn connect(service_name: &str, timeout: Duration) -> Result<TcpStream, Error> {
+    let deadline = Instant::now() + timeout;
+
+    let process = async {
+        // by setting the query interval higher than the timeout we ensure we only make one query
+        let stream = crate::discover::all(service_name, timeout * 2)?.listen();
+        pin_mut!(stream);
+
+        while let Some(response) = stream.next().await {
+            let response = response?;
+            let mut addrs = response.socket_addrs();
+
+            if addrs.is_empty() {
+                for record in response.answers.iter() {
+                    if let RecordKind::PTR(ref instance_name) = record.kind {
+                        let remaining = deadline.saturating_duration_since(Instant::now());
+
+                        match crate::resolve_service(instance_name, remaining).await {
+                            Ok(Some(instance)) => addrs.extend(instance.socket_addrs()),
+                            Ok(None) => log::debug!("{} did not answer in time", instance_name),
+                            Err(e) => log::debug!("failed to resolve {}: {}", instance_name, e),
+                        }
+                    }
+                }
+            }
+
+            for addr in addrs {
+                match TcpStream::connect(addr).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => log::debug!("failed to connect to {}: {}", addr, e),
+                }
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::NotFound, "no instance could be connected to").into())
+    };
+
+    async_std::future::timeout(timeout, process).await?
+}
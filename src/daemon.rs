@@ -0,0 +1,90 @@
+//! Detecting a system mDNS daemon, such as avahi or mDNSResponder.
+//!
+//! Such daemons share port 5353 with us, and depending on the platform may
+//! be handed some of the datagrams we would otherwise receive. On platforms
+//! we don't know how to check, no daemon is reported.
+
+use async_std::net::UdpSocket;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod imp {
+    use crate::mdns::MULTICAST_PORT;
+    use async_std::net::UdpSocket;
+    use std::fs;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    /// The socket tables listing every bound UDP socket on the host.
+    const SOCKET_TABLES: [&str; 2] = ["/proc/net/udp", "/proc/net/udp6"];
+
+    pub fn present(own_sockets: &[&UdpSocket]) -> bool {
+        let own_inodes: Vec<u64> = own_sockets.iter().filter_map(|s| inode(s)).collect();
+
+        SOCKET_TABLES
+            .iter()
+            .filter_map(|table| fs::read_to_string(table).ok())
+            .flat_map(|table| bound_inodes(&table))
+            .any(|inode| !own_inodes.contains(&inode))
+    }
+
+    /// Gets the inodes of the sockets bound to the mDNS port in a socket table.
+    fn bound_inodes(table: &str) -> Vec<u64> {
+        table
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let (_, port) = fields.get(1)?.rsplit_once(':')?;
+
+                if u16::from_str_radix(port, 16).ok()? != MULTICAST_PORT {
+                    return None;
+                }
+
+                fields.get(9)?.parse().ok()
+            })
+            .collect()
+    }
+
+    fn inode(socket: &UdpSocket) -> Option<u64> {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+
+        if unsafe { libc::fstat(socket.as_raw_fd(), &mut stat) } < 0 {
+            return None;
+        }
+
+        Some(stat.st_ino as u64)
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+mod imp {
+    use async_std::net::UdpSocket;
+    use std::path::Path;
+
+    /// The socket mDNSResponder listens for its clients on.
+    const DAEMON_SOCKET: &str = "/var/run/mDNSResponder";
+
+    pub fn present(_: &[&UdpSocket]) -> bool {
+        Path::new(DAEMON_SOCKET).exists()
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+mod imp {
+    use async_std::net::UdpSocket;
+
+    pub fn present(_: &[&UdpSocket]) -> bool {
+        false
+    }
+}
+
+/// Checks whether a process other than us has a socket bound to the mDNS
+/// port, ignoring `own_sockets`.
+pub fn present(own_sockets: &[&UdpSocket]) -> bool {
+    imp::present(own_sockets)
+}
@@ -22,31 +22,378 @@
 //! }
 //! ```
 
-use crate::{mDNSListener, Error, Response};
+use crate::{
+    mDNSListener, Clock, DeviceEvent, DiscoveryCache, DiscoveryStore, Error, Interface,
+    LatencyHistogram, ListenerStats, Query, Record, RecordKey, RecordKind, Response,
+    ServiceInstance, SocketInfo, SystemClock,
+};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::mdns::{mDNSSender, mdns_interface};
-use futures_core::Stream;
-use futures_util::{future::ready, stream::select, StreamExt};
-use std::net::Ipv4Addr;
+use crate::mdns::{
+    default_interface_addr, is_same_interface, mDNSSender, mdns_interface,
+    mdns_interface_from_socket, mdns_interface_split, mdns_socket_v6, multicast_interfaces,
+    IpVersion, SocketConfig,
+};
+use crate::replay::Recorder;
+use async_lock::Semaphore;
+use futures_core::{stream::FusedStream, Stream};
+use futures_util::{
+    future::{self, abortable, join_all, ready, AbortHandle, Either},
+    pin_mut,
+    stream::{self, select},
+    StreamExt,
+};
+use if_addrs::IfAddr;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::{ControlFlow, Range};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// How often we remove the expired records of a store.
+const STORE_EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often we check for interfaces appearing or disappearing.
+const INTERFACE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long we wait for the answer to a follow-up query by default.
+const FOLLOW_UP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many follow-up queries can be outstanding at once by default.
+const DEFAULT_MAX_INFLIGHT: usize = 16;
+
+/// The name whose `PTR` records list every service type on the network.
+///
+/// See [RFC 6763 §9](https://tools.ietf.org/html/rfc6763#section-9).
+const SERVICE_TYPE_ENUMERATION_NAME: &str = "_services._dns-sd._udp.local";
+
+/// How many queries each stage of a network inventory sends.
+const INVENTORY_QUERY_COUNT: usize = 3;
+
+/// The delays between the initial burst of queries in continuous discovery.
+const INITIAL_QUERY_DELAYS: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(4),
+];
+
+/// The family of addresses we want to learn about.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// Only IPv4 (`A`) addresses.
+    V4,
+    /// Only IPv6 (`AAAA`) addresses.
+    V6,
+    /// Both IPv4 and IPv6 addresses.
+    Both,
+}
+
+impl AddressFamily {
+    /// Checks whether a record should be kept, which is the case for every
+    /// record that is not an address of another family.
+    fn keeps(self, record: &Record) -> bool {
+        !matches!(
+            (self, &record.kind),
+            (AddressFamily::V4, RecordKind::AAAA(..)) | (AddressFamily::V6, RecordKind::A(..))
+        )
+    }
+}
+
+/// A predicate responses must match to be yielded.
+type ResponseFilter = Box<dyn Fn(&Response) -> bool + Send + Sync>;
 
 /// A multicast DNS discovery request.
 ///
-/// This represents a single lookup of a single service name.
+/// This represents a single lookup of a single service name, or of several
+/// when created with [`services`].
 ///
 /// This object can be iterated over to yield the received mDNS responses.
 pub struct Discovery {
     service_name: String,
 
+    /// Further services browsed for alongside `service_name`.
+    extra_service_names: Vec<String>,
+
     mdns_sender: mDNSSender,
     mdns_listener: mDNSListener,
 
     /// Whether we should ignore empty responses.
     ignore_empty: bool,
 
+    /// Whether we should yield responses regardless of the name they answer.
+    match_all: bool,
+
+    /// Predicates every yielded response must satisfy.
+    filters: Vec<ResponseFilter>,
+
+    /// The family of addresses we keep in responses.
+    address_family: AddressFamily,
+
+    /// The type of record we ask for in our queries.
+    query_type: dns_parser::QueryType,
+
     /// The interval we should send mDNS queries.
     send_request_interval: Duration,
+
+    /// How long responses to each query are gathered for when listening in windows.
+    per_query_window: Duration,
+
+    /// How long we listen for before ending the stream, if limited.
+    session_deadline: Option<Duration>,
+
+    /// The cache we keep up to date with received responses, if any.
+    cache: Option<Arc<Mutex<DiscoveryCache>>>,
+
+    /// The store we keep up to date with received responses, if any.
+    store: Option<Arc<dyn DiscoveryStore>>,
+
+    /// The interfaces we have joined the multicast group on, when joining all of them.
+    interfaces: Vec<Interface>,
+
+    /// Whether we joined the multicast group on all interfaces.
+    all_interfaces: bool,
+
+    /// The single interface we joined the multicast group on, if not all of them.
+    interface_addr: Option<Ipv4Addr>,
+
+    /// Whether loopback is among the interfaces we join.
+    include_loopback: bool,
+
+    /// The interfaces we have been asked not to listen on.
+    excluded_interfaces: Vec<Interface>,
+
+    /// Whether we should join and leave interfaces as they come and go.
+    follow_interface_changes: bool,
+
+    /// Whether we should query for the details of instances we only learn the name of.
+    auto_resolve: bool,
+
+    /// Whether follow-up queries also ask for the service again.
+    combined_query: bool,
+
+    /// The most instances tracked for events at once, if limited.
+    max_instances: Option<usize>,
+
+    /// The most follow-up queries outstanding at once.
+    max_inflight: usize,
+
+    /// Permits for outstanding follow-up queries.
+    inflight: Arc<Semaphore>,
+
+    /// How long each follow-up query is given before it is skipped.
+    follow_up_timeout: Duration,
+
+    /// How long to gather further datagrams from the same responder for, if
+    /// they are merged.
+    correlation_window: Option<Duration>,
+
+    /// How long to gather further records about an instance for after it is
+    /// first answered with, if they are merged.
+    completion_window: Option<Duration>,
+
+    /// How many responses about an instance must be seen before any are
+    /// yielded.
+    min_confirmations: usize,
+
+    /// Whether `listen` sends a query as soon as it starts.
+    query_on_listen: bool,
+
+    /// Whether responses from outside our local subnets are dropped.
+    same_subnet_only: bool,
+
+    /// Reads the time our queries are scheduled and our windows timed by.
+    clock: Arc<dyn Clock>,
+}
+
+/// A response along with the host names its addresses resolve to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NamedResponse {
+    pub response: Response,
+    /// The host name of each address in the response that has one.
+    pub hostnames: HashMap<IpAddr, String>,
+}
+
+/// Takes an inventory of every instance of every service type on the network.
+///
+/// Half of `timeout` is spent enumerating the service types, and the rest
+/// browsing all of them at once from a single socket, completing instances
+/// with a bounded number of follow-up queries. Whatever was found once the
+/// time is up is returned, including service types without any resolved
+/// instances.
+pub async fn everything(timeout: Duration) -> Result<HashMap<String, Vec<ServiceInstance>>, Error> {
+    let mut service_types: Vec<String> = Vec::new();
+
+    let discovery = all(SERVICE_TYPE_ENUMERATION_NAME, timeout)?;
+    let clock = discovery.clock.clone();
+    let deadline = clock.now() + timeout;
+
+    // Enumeration gets a block of its own so that its queries stop when it
+    // ends, rather than carrying on alongside browsing.
+    {
+        let stream = discovery.burst(INVENTORY_QUERY_COUNT);
+        pin_mut!(stream);
+
+        let enumerate = async {
+            while let Some(response) = stream.next().await {
+                for record in response?.answers.iter() {
+                    match record.kind {
+                        RecordKind::PTR(ref service_type)
+                            if record.name == SERVICE_TYPE_ENUMERATION_NAME
+                                && !service_types.contains(service_type) =>
+                        {
+                            service_types.push(service_type.clone())
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            Ok::<_, Error>(())
+        };
+
+        // Running out of time is expected, as that's how enumeration ends.
+        if let Ok(result) = async_std::future::timeout(timeout / 2, enumerate).await {
+            result?;
+        }
+    }
+
+    let mut inventory: HashMap<String, Vec<ServiceInstance>> = service_types
+        .iter()
+        .map(|service_type| (service_type.clone(), Vec::new()))
+        .collect();
+
+    if service_types.is_empty() {
+        return Ok(inventory);
+    }
+
+    let stream = services(&service_types, timeout)?
+        .auto_resolve(true)
+        .burst(INVENTORY_QUERY_COUNT);
+    pin_mut!(stream);
+
+    let browse = async {
+        while let Some(response) = stream.next().await {
+            for found in attribute_services(response?, &service_types) {
+                let instance = found.instance.as_ref().and_then(|instance_name| {
+                    ServiceInstance::from_response(instance_name, &found.response)
+                });
+                let instance = match instance {
+                    Some(instance) => instance,
+                    None => continue,
+                };
+
+                let instances = inventory.entry(found.service_type).or_default();
+                match instances
+                    .iter_mut()
+                    .find(|known| known.name == instance.name)
+                {
+                    Some(known) => *known = instance,
+                    None => instances.push(instance),
+                }
+            }
+        }
+
+        Ok::<_, Error>(())
+    };
+
+    let remaining = deadline.saturating_duration_since(clock.now());
+    if let Ok(result) = async_std::future::timeout(remaining, browse).await {
+        result?;
+    }
+
+    Ok(inventory)
+}
+
+/// Browses for the instances of a service for `timeout`, resolving each and
+/// keying them by instance name.
+///
+/// A few queries are sent, and instances named without their `SRV` record are
+/// queried for it. Later responses about an instance update it, keeping its
+/// previous addresses or `TXT` record if they leave those out. Instances
+/// whose `SRV` record never arrived are left out.
+pub async fn collect_by_instance(
+    service_name: &str,
+    timeout: Duration,
+) -> Result<HashMap<String, ServiceInstance>, Error> {
+    let service_names = [service_name.to_owned()];
+    let mut instances: HashMap<String, ServiceInstance> = HashMap::new();
+
+    let stream = all(service_name, timeout)?
+        .auto_resolve(true)
+        .burst(INVENTORY_QUERY_COUNT);
+    pin_mut!(stream);
+
+    let browse = async {
+        while let Some(response) = stream.next().await {
+            for found in attribute_services(response?, &service_names) {
+                let instance = found.instance.as_ref().and_then(|instance_name| {
+                    ServiceInstance::from_response(instance_name, &found.response)
+                });
+                let mut instance = match instance {
+                    Some(instance) => instance,
+                    None => continue,
+                };
+
+                if let Some(old) = instances.get(&instance.name) {
+                    if instance.addresses.is_empty() {
+                        instance.addresses = old.addresses.clone();
+                    }
+                    if instance.txt.is_empty() {
+                        instance.txt = old.txt.clone();
+                    }
+                }
+
+                instances.insert(instance.name.clone(), instance);
+            }
+        }
+
+        Ok::<_, Error>(())
+    };
+
+    // Running out of time is expected, as that's how browsing ends.
+    if let Ok(result) = async_std::future::timeout(timeout, browse).await {
+        result?;
+    }
+
+    Ok(instances)
+}
+
+/// A response along with the browsed service it answers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceResponse {
+    /// The service type the response answers, such as `_googlecast._tcp.local`.
+    pub service_type: String,
+    /// The instance of the service the response is about, if it names one.
+    pub instance: Option<String>,
+    pub response: Response,
+}
+
+/// What was heard during one window of
+/// [`Discovery::listen_windows`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScanOutcome {
+    /// The responses received during the window.
+    Responses(Vec<Response>),
+    /// Nothing answered the query before the window closed.
+    NoResponders,
+}
+
+/// A cloneable handle that queries for a discovery's services on demand.
+#[derive(Clone, Debug)]
+pub struct DiscoveryScanner {
+    sender: mDNSSender,
+}
+
+impl DiscoveryScanner {
+    /// Queries for the services straight away.
+    pub async fn scan(&self) -> Result<(), Error> {
+        self.sender.clone().send_request().await
+    }
 }
 
 /// Gets an iterator over all responses for a given service on all interfaces.
@@ -57,28 +404,250 @@ where
     interface(service_name, mdns_query_interval, Ipv4Addr::new(0, 0, 0, 0))
 }
 
+/// Gets an iterator over all responses for several services on all interfaces.
+///
+/// Every service is queried for at each interval. Use
+/// [`Discovery::listen_by_service`] to learn which service each response
+/// answers.
+///
+/// # Panics
+///
+/// Panics if `service_names` is empty.
+pub fn services<I, S>(service_names: I, mdns_query_interval: Duration) -> Result<Discovery, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut service_names = service_names
+        .into_iter()
+        .map(|service_name| service_name.as_ref().to_owned());
+    let service_name = service_names
+        .next()
+        .expect("at least one service name must be given");
+
+    let mut discovery = all(service_name, mdns_query_interval)?;
+    discovery.extra_service_names = service_names.collect();
+    discovery
+        .mdns_sender
+        .set_extra_service_names(discovery.extra_service_names.clone());
+
+    Ok(discovery)
+}
+
+/// Gets an iterator over all responses for a given service on the interface
+/// carrying the default route.
+///
+/// This is the interface used to reach the gateway, which is usually the one
+/// on the LAN we care about. If there is no default route, all interfaces are
+/// used instead.
+pub fn auto<S>(service_name: S, mdns_query_interval: Duration) -> Result<Discovery, Error>
+where
+    S: AsRef<str>,
+{
+    match default_interface_addr() {
+        Some(interface_addr) => interface(service_name, mdns_query_interval, interface_addr),
+        None => all(service_name, mdns_query_interval),
+    }
+}
+
+/// Gets an iterator over all responses for a subtype of a service on all interfaces.
+///
+/// The subtype may be given with or without its leading underscore, so
+/// browsing for `printer` on `_http._tcp.local` queries
+/// `_printer._sub._http._tcp.local`.
+pub fn subtype<S, T>(
+    subtype: S,
+    service_type: T,
+    mdns_query_interval: Duration,
+) -> Result<Discovery, Error>
+where
+    S: AsRef<str>,
+    T: AsRef<str>,
+{
+    all(
+        subtype_name(subtype.as_ref(), service_type.as_ref()),
+        mdns_query_interval,
+    )
+}
+
+/// Composes the name of a service subtype.
+fn subtype_name(subtype: &str, service_type: &str) -> String {
+    format!("_{}._sub.{}", subtype.trim_start_matches('_'), service_type)
+}
+
+/// Gets an iterator over all responses for a given service on all interfaces,
+/// using a custom socket configuration.
+pub fn all_with_config<S>(
+    service_name: S,
+    mdns_query_interval: Duration,
+    config: SocketConfig,
+) -> Result<Discovery, Error>
+where
+    S: AsRef<str>,
+{
+    interface_with_config(
+        service_name,
+        mdns_query_interval,
+        Ipv4Addr::new(0, 0, 0, 0),
+        config,
+    )
+}
+
 /// Gets an iterator over all responses for a given service on a given interface.
 pub fn interface<S>(
     service_name: S,
     mdns_query_interval: Duration,
     interface_addr: Ipv4Addr,
 ) -> Result<Discovery, Error>
+where
+    S: AsRef<str>,
+{
+    interface_with_config(
+        service_name,
+        mdns_query_interval,
+        interface_addr,
+        SocketConfig::default(),
+    )
+}
+
+/// Gets an iterator over all responses for a given service on a given interface,
+/// using a custom socket configuration.
+pub fn interface_with_config<S>(
+    service_name: S,
+    mdns_query_interval: Duration,
+    interface_addr: Ipv4Addr,
+    config: SocketConfig,
+) -> Result<Discovery, Error>
 where
     S: AsRef<str>,
 {
     let service_name = service_name.as_ref().to_string();
-    let (mdns_listener, mdns_sender) = mdns_interface(service_name.clone(), interface_addr)?;
+    let (mdns_listener, mdns_sender, interfaces) =
+        mdns_interface(service_name.clone(), interface_addr, &config)?;
 
-    Ok(Discovery {
+    Ok(Discovery::new(
         service_name,
+        mdns_query_interval,
+        mdns_listener,
         mdns_sender,
+        interfaces,
+        Some(interface_addr),
+        config.includes_loopback(),
+    ))
+}
+
+/// Gets an iterator over all responses for a given service, using a socket
+/// the application already has, such as one shared with a responder.
+///
+/// The socket is used both to send queries and to receive responses. It
+/// should already have joined the mDNS multicast group on the interfaces of
+/// interest, as nothing is joined or left on it.
+pub fn from_async_socket<S>(
+    service_name: S,
+    socket: Arc<async_std::net::UdpSocket>,
+    mdns_query_interval: Duration,
+) -> Discovery
+where
+    S: AsRef<str>,
+{
+    let service_name = service_name.as_ref().to_string();
+    let (mdns_listener, mdns_sender) = mdns_interface_from_socket(service_name.clone(), socket);
+
+    Discovery::new(
+        service_name,
+        mdns_query_interval,
         mdns_listener,
-        ignore_empty: true,
-        send_request_interval: mdns_query_interval,
-    })
+        mdns_sender,
+        Vec::new(),
+        None,
+        false,
+    )
+}
+
+/// Gets an iterator over all responses for a given service on a given interface,
+/// using separate sockets for sending and receiving.
+///
+/// This is mostly useful for tests running a responder and a browser in the
+/// same process, which need multicast loopback to see each other's packets.
+/// Linux and macOS apply loopback to the packets a socket sends, whereas
+/// Windows applies it to the packets a socket receives, so each socket's
+/// setting is given separately.
+pub fn split_sockets<S>(
+    service_name: S,
+    mdns_query_interval: Duration,
+    interface_addr: Ipv4Addr,
+    send_loopback: bool,
+    recv_loopback: bool,
+) -> Result<Discovery, Error>
+where
+    S: AsRef<str>,
+{
+    let service_name = service_name.as_ref().to_string();
+    let (mdns_listener, mdns_sender, interfaces) = mdns_interface_split(
+        service_name.clone(),
+        interface_addr,
+        &SocketConfig::default(),
+        send_loopback,
+        recv_loopback,
+    )?;
+
+    Ok(Discovery::new(
+        service_name,
+        mdns_query_interval,
+        mdns_listener,
+        mdns_sender,
+        interfaces,
+        Some(interface_addr),
+        false,
+    ))
 }
 
 impl Discovery {
+    fn new(
+        service_name: String,
+        mdns_query_interval: Duration,
+        mdns_listener: mDNSListener,
+        mdns_sender: mDNSSender,
+        interfaces: Vec<Interface>,
+        interface_addr: Option<Ipv4Addr>,
+        include_loopback: bool,
+    ) -> Self {
+        Discovery {
+            service_name,
+            extra_service_names: Vec::new(),
+            mdns_sender,
+            mdns_listener,
+            ignore_empty: true,
+            match_all: false,
+            filters: Vec::new(),
+            address_family: AddressFamily::Both,
+            query_type: dns_parser::QueryType::PTR,
+            send_request_interval: mdns_query_interval,
+            per_query_window: mdns_query_interval,
+            session_deadline: None,
+            cache: None,
+            store: None,
+            interfaces,
+            all_interfaces: interface_addr.is_some_and(|addr| addr.is_unspecified()),
+            interface_addr: interface_addr.filter(|addr| !addr.is_unspecified()),
+            include_loopback,
+            excluded_interfaces: Vec::new(),
+            follow_interface_changes: false,
+            auto_resolve: false,
+            combined_query: false,
+            max_instances: None,
+            max_inflight: DEFAULT_MAX_INFLIGHT,
+            inflight: Arc::new(Semaphore::new(DEFAULT_MAX_INFLIGHT)),
+            follow_up_timeout: FOLLOW_UP_TIMEOUT,
+            correlation_window: None,
+            completion_window: None,
+            min_confirmations: 1,
+            query_on_listen: false,
+            same_subnet_only: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
     /// Sets whether or not we should ignore empty responses.
     ///
     /// Defaults to `true`.
@@ -87,48 +656,2082 @@ impl Discovery {
         self
     }
 
-    pub fn listen(self) -> impl Stream<Item = Result<Response, Error>> {
-        let ignore_empty = self.ignore_empty;
-        let service_name = self.service_name;
-        let response_stream = self.mdns_listener.listen().map(StreamResult::Response);
-        let sender = self.mdns_sender.clone();
+    /// Sets whether we should yield every response received on the multicast
+    /// group, rather than only those answering our query.
+    ///
+    /// This is useful to monitor all mDNS traffic on the network. Defaults to `false`.
+    pub fn match_all(mut self, match_all: bool) -> Self {
+        self.match_all = match_all;
+        self
+    }
 
-        let interval_stream = async_std::stream::interval(self.send_request_interval)
-            // I don't like the double clone, I can't find a prettier way to do this
-            .map(move |_| {
-                let mut sender = sender.clone();
-                async_std::task::spawn(async move {
-                    let _ = sender.send_request().await;
-                });
-                StreamResult::Interval
-            });
+    /// Sets whether we should drop our own queries when they are echoed back
+    /// to us, as happens with multicast loopback or on some switches.
+    ///
+    /// Defaults to `true`.
+    pub fn ignore_own_queries(mut self, ignore: bool) -> Self {
+        self.mdns_listener.set_ignore_own_queries(ignore);
+        self
+    }
 
-        let stream = select(response_stream, interval_stream);
-        stream
-            .filter_map(|stream_result| {
-                async {
-                    match stream_result {
-                        StreamResult::Interval => None,
-                        StreamResult::Response(res) => Some(res),
-                    }
-                }
-            })
-            .filter(move |res| {
-                ready(match res {
-                    Ok(response) => {
-                        (!response.is_empty() || !ignore_empty)
-                            && response
-                                .answers
-                                .iter()
-                                .any(|record| record.name == service_name)
-                    }
-                    Err(_) => true,
-                })
-            })
+    /// Sets whether we should recover the readable records of responses that
+    /// fail to parse, rather than dropping them.
+    ///
+    /// Some devices send names that are not valid ASCII, for example.
+    /// Defaults to `false`.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.mdns_listener.set_lenient(lenient);
+        self
     }
-}
 
-enum StreamResult {
-    Interval,
-    Response(Result<Response, Error>),
+    /// Sets whether responses that fail to parse, even
+    /// [leniently](Self::lenient), are yielded as
+    /// [`Error::ParseError`](crate::Error::ParseError) rather than only being
+    /// logged.
+    ///
+    /// This helps debug devices that send malformed packets. Listening
+    /// carries on after such an error. Defaults to `false`.
+    pub fn surface_parse_errors(mut self, surface: bool) -> Self {
+        self.mdns_listener.set_surface_parse_errors(surface);
+        self
+    }
+
+    /// Limits how many received datagrams are parsed per second.
+    ///
+    /// Under a flood of packets, from a misbehaving device or otherwise,
+    /// datagrams over the limit are dropped without parsing so that the
+    /// discovery task stays responsive. Drops are counted in [`Discovery::stats`].
+    pub fn max_parse_rate(mut self, per_sec: u32) -> Self {
+        self.mdns_listener.set_max_parse_rate(per_sec);
+        self
+    }
+
+    /// Ends discovery once `max_bytes` bytes of datagrams have been received.
+    ///
+    /// This bounds how much traffic is processed on metered or constrained
+    /// devices. The bytes received so far are counted in
+    /// [`Discovery::stats`]. Defaults to no limit.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.mdns_listener.set_max_bytes(max_bytes);
+        self
+    }
+
+    /// Restricts the addresses in yielded responses to a single family.
+    ///
+    /// Address records of the other family are removed from responses, so
+    /// that methods such as [`Response::socket_addrs`] and [`Discovery::addresses`]
+    /// never return them. Defaults to [`AddressFamily::Both`].
+    pub fn address_family(mut self, address_family: AddressFamily) -> Self {
+        self.address_family = address_family;
+        self
+    }
+
+    /// Sets the type of record we ask for in our queries.
+    ///
+    /// Only responses answering a question of this type for the service name
+    /// are yielded. Defaults to `PTR`.
+    pub fn query_type(mut self, query_type: dns_parser::QueryType) -> Self {
+        self.query_type = query_type;
+        self.mdns_sender.set_query_type(query_type);
+        self
+    }
+
+    /// Sets whether we should complete the resolution of instances we only
+    /// learn the name of.
+    ///
+    /// When a response has a `PTR` record naming an instance, but not the
+    /// instance's `SRV` record, the instance is queried for directly. Its
+    /// records are then merged into the original response, which is yielded
+    /// again. Defaults to `false`.
+    pub fn auto_resolve(mut self, auto_resolve: bool) -> Self {
+        self.auto_resolve = auto_resolve;
+        self
+    }
+
+    /// Sets whether the follow-up queries of [`auto_resolve`](Self::auto_resolve)
+    /// ask for the service's `PTR` records along with the instance's `SRV`
+    /// and `TXT` records, all in one packet.
+    ///
+    /// This browses and resolves in a single round trip, but not every
+    /// responder answers packets with several questions. Defaults to `false`.
+    pub fn combined_query(mut self, combined_query: bool) -> Self {
+        self.combined_query = combined_query;
+        self
+    }
+
+    /// Sets the most instances [`events`](Self::events) keeps track of.
+    ///
+    /// When a new instance would exceed the limit, the least recently seen
+    /// one is forgotten and reported as [`DeviceEvent::Removed`] first. This
+    /// keeps memory bounded on networks with thousands of devices. Defaults to
+    /// no limit.
+    pub fn max_instances(mut self, max_instances: usize) -> Self {
+        assert!(max_instances > 0, "max_instances must be at least 1");
+        self.max_instances = Some(max_instances);
+        self
+    }
+
+    /// Sets the most follow-up queries, from [`auto_resolve`](Self::auto_resolve)
+    /// or [`with_reverse_names`](Self::with_reverse_names), that may be
+    /// outstanding at once.
+    ///
+    /// Further queries wait for an earlier one to be answered or time out.
+    /// Defaults to 16.
+    pub fn max_inflight(mut self, max_inflight: usize) -> Self {
+        assert!(max_inflight > 0, "max_inflight must be at least 1");
+        self.max_inflight = max_inflight;
+        self.inflight = Arc::new(Semaphore::new(max_inflight));
+        self
+    }
+
+    /// Sets how long each follow-up query, from
+    /// [`auto_resolve`](Self::auto_resolve) or
+    /// [`with_reverse_names`](Self::with_reverse_names), is given to be sent
+    /// and answered.
+    ///
+    /// A follow-up that takes longer is skipped, so a responder that never
+    /// answers can't hold up the rest of the discovery. Defaults to 2 seconds.
+    pub fn follow_up_timeout(mut self, timeout: Duration) -> Self {
+        self.follow_up_timeout = timeout;
+        self
+    }
+
+    /// Merges the datagrams a responder sends within `window` of each other
+    /// into one response.
+    ///
+    /// Some devices answer with the `PTR` record in one datagram and the
+    /// `SRV`, `TXT` and address records in others a few milliseconds later,
+    /// which leaves each response incomplete on its own. The merged response
+    /// is yielded once `window` has passed since the first datagram, so it is
+    /// delayed by up to that long. Responders are told apart by the address
+    /// they send from.
+    pub fn correlate(mut self, window: Duration) -> Self {
+        self.correlation_window = Some(window);
+        self
+    }
+
+    /// Holds back each response naming an instance for `window`, merging in
+    /// the records about the instance and its host that arrive meanwhile.
+    ///
+    /// A `PTR` answer is often followed shortly by the instance's `SRV`,
+    /// `TXT` and address records in separate datagrams, possibly from other
+    /// responders. Merging them means the instance is complete when yielded,
+    /// and saves [auto-resolving](Self::auto_resolve) it. Too short a window
+    /// misses records, while too long a window delays every instance.
+    /// Responses that don't name an instance are yielded straight away.
+    pub fn completion_window(mut self, window: Duration) -> Self {
+        self.completion_window = Some(window);
+        self
+    }
+
+    /// Only yields the responses about an instance once `confirmations` of
+    /// them have been received, such as in answer to several queries.
+    ///
+    /// Responses before that are dropped, which filters out stray or spoofed
+    /// single packets. Responses that aren't about any instance are yielded
+    /// as usual. Defaults to 1, yielding every response.
+    pub fn min_confirmations(mut self, confirmations: usize) -> Self {
+        assert!(confirmations > 0, "min_confirmations must be at least 1");
+        self.min_confirmations = confirmations;
+        self
+    }
+
+    /// Sets whether [`listen`](Self::listen) sends a query as soon as it
+    /// starts, rather than waiting for the first interval to pass.
+    ///
+    /// Defaults to `false`.
+    pub fn query_on_listen(mut self, query_on_listen: bool) -> Self {
+        self.query_on_listen = query_on_listen;
+        self
+    }
+
+    /// Sets whether responses sent from outside the subnets of our interfaces
+    /// are dropped.
+    ///
+    /// This guards against responses leaking in from other networks, such as
+    /// over a VPN, and spoofed ones. The subnets are read when listening
+    /// starts; responses that were not received over the network, such as
+    /// replayed ones, are kept. Defaults to `false`.
+    pub fn same_subnet_only(mut self, same_subnet_only: bool) -> Self {
+        self.same_subnet_only = same_subnet_only;
+        self
+    }
+
+    /// Sets how long the responses to each query are gathered for by
+    /// [`listen_windows`](Self::listen_windows) before the next query is sent.
+    ///
+    /// Defaults to the query interval.
+    pub fn per_query_window(mut self, window: Duration) -> Self {
+        self.per_query_window = window;
+        self
+    }
+
+    /// Sets how long we listen for before the stream ends, however it is
+    /// listened to.
+    ///
+    /// Defaults to listening indefinitely.
+    pub fn session_deadline(mut self, deadline: Duration) -> Self {
+        self.session_deadline = Some(deadline);
+        self
+    }
+
+    /// Sets whether the recursion desired flag is set on our queries.
+    ///
+    /// Defaults to `false`, as is correct for mDNS.
+    pub fn recursion_desired(mut self, recursion_desired: bool) -> Self {
+        self.mdns_sender.set_recursion_desired(recursion_desired);
+        self
+    }
+
+    /// Sets whether our queries ask for responses to be sent by unicast.
+    ///
+    /// Defaults to `false`.
+    pub fn prefer_unicast(mut self, prefer_unicast: bool) -> Self {
+        self.mdns_sender.set_prefer_unicast(prefer_unicast);
+        self
+    }
+
+    /// Sets the range of the random delay before each of our queries is sent.
+    ///
+    /// Defaults to 20-120ms, as recommended by
+    /// [RFC 6762 §5.2](https://tools.ietf.org/html/rfc6762#section-5.2), so
+    /// that hosts running the same discovery don't all query at once. An
+    /// empty range sends queries straight away.
+    pub fn jitter(mut self, jitter: Range<Duration>) -> Self {
+        self.mdns_sender.set_jitter(jitter);
+        self
+    }
+
+    /// Calls `hook` with the bytes of every query we send and where it was
+    /// sent to.
+    ///
+    /// This helps confirm what is actually on the wire when nothing seems to
+    /// answer.
+    pub fn on_query_sent<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&[u8], SocketAddr) + Send + Sync + 'static,
+    {
+        self.mdns_sender.set_on_query_sent(hook);
+        self
+    }
+
+    /// Keeps a cache up to date with every response we yield.
+    ///
+    /// Every response received is cached, even those that are not yielded,
+    /// such as the answers to our refresh queries. Records in the cache are
+    /// re-queried when they reach 80%, 85%, 90% and 95% of their TTL, so that
+    /// they are refreshed before they lapse. Our queries also list the cached
+    /// records as known answers, so that responders don't repeat them.
+    pub fn cache(mut self, cache: Arc<Mutex<DiscoveryCache>>) -> Self {
+        self.mdns_sender.set_known_answers(cache.clone());
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sets the clock our queries are scheduled by, and that times response
+    /// windows and round trips.
+    ///
+    /// Defaults to the [`SystemClock`]. A [cache](Self::cache) reads the time
+    /// from a clock of its own, given to
+    /// [`DiscoveryCache::with_clock`](crate::DiscoveryCache::with_clock).
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        self.mdns_sender.set_clock(clock.clone());
+        self.mdns_listener.set_clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// Sets the largest query packet we send, in bytes.
+    ///
+    /// When our queries list many known answers, those that don't fit are
+    /// sent in further packets rather than risking fragmentation. Defaults
+    /// to 1232 bytes, which fits any IPv6-capable link.
+    pub fn max_query_size(mut self, max_query_size: usize) -> Self {
+        self.mdns_sender.set_max_query_size(max_query_size);
+        self
+    }
+
+    /// Sends our queries from the interface with address `interface_addr`.
+    ///
+    /// When listening on every interface, the OS still picks a single one to
+    /// send multicast queries from, which may not reach the devices on a
+    /// multi-homed host. This overrides that choice without changing which
+    /// interfaces responses are received on.
+    pub fn send_from(mut self, interface_addr: Ipv4Addr) -> Result<Self, Error> {
+        self.mdns_sender.set_multicast_interface(interface_addr)?;
+        Ok(self)
+    }
+
+    /// Sets which IP versions we query and listen over.
+    ///
+    /// Defaults to [`IpVersion::V4`]. Using IPv6 creates a socket that joins
+    /// the `ff02::fb` group on the interfaces we have joined, or on every
+    /// interface with an IPv6 address when they aren't known, which suits
+    /// networks where mDNS over IPv4 has been disabled. Following interface
+    /// changes only applies to IPv4.
+    pub fn ip_version(mut self, ip_version: IpVersion) -> Result<Self, Error> {
+        let socket_v6 = if ip_version.includes_v6() {
+            let interfaces = match self.interface_addr {
+                // The group is joined on the same interface, found by its index.
+                Some(interface_addr) => {
+                    let interfaces: Vec<Interface> = if_addrs::get_if_addrs()?
+                        .into_iter()
+                        .filter(|interface| interface.ip() == IpAddr::V4(interface_addr))
+                        .collect();
+
+                    if interfaces.is_empty() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            format!("no interface has the address {}", interface_addr),
+                        )
+                        .into());
+                    }
+                    interfaces
+                }
+                None => self.interfaces.clone(),
+            };
+
+            Some(mdns_socket_v6(&interfaces, self.include_loopback)?)
+        } else {
+            None
+        };
+
+        self.mdns_sender.set_ip_version(
+            ip_version,
+            socket_v6.as_ref().map(|(socket, _)| socket.clone()),
+        );
+        self.mdns_listener.set_ip_version(ip_version, socket_v6);
+        Ok(self)
+    }
+
+    /// Keeps a store up to date with every response we yield.
+    ///
+    /// Unlike a [cache](Self::cache), a store can keep records somewhere
+    /// other than this process's memory, such as a database shared with other
+    /// processes. Its expired records are removed every second. Failures to
+    /// update the store are logged and otherwise ignored.
+    pub fn store(mut self, store: Arc<dyn DiscoveryStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Records every datagram we receive to a file, to be replayed later with
+    /// [`replay::session`](crate::replay::session).
+    pub fn record_to<P: AsRef<Path>>(mut self, path: P) -> Result<Self, Error> {
+        self.mdns_listener.record_to(Recorder::create(path)?);
+        Ok(self)
+    }
+
+    /// Only yields the responses matching a predicate.
+    ///
+    /// The predicate sees responses after the built-in filtering, such as by
+    /// service name and address family, has been applied. Several predicates
+    /// can be added, and all of them must match. Errors are always yielded.
+    pub fn filter<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Response) -> bool + Send + Sync + 'static,
+    {
+        self.filters.push(Box::new(predicate));
+        self
+    }
+
+    /// Stops listening on the interfaces matching a predicate.
+    ///
+    /// This is useful to prune noisy virtual adapters (such as `veth*` or
+    /// `docker*`), which can loop traffic back and cause duplicate responses.
+    /// It only applies when discovering on all interfaces.
+    pub fn exclude_interfaces<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Interface) -> bool,
+    {
+        let listener = &self.mdns_listener;
+        let excluded_interfaces = &mut self.excluded_interfaces;
+
+        self.interfaces.retain(|interface| {
+            if !predicate(interface) {
+                return true;
+            }
+
+            excluded_interfaces.push(interface.clone());
+
+            if let IpAddr::V4(addr) = interface.ip() {
+                if let Err(e) = listener.leave_interface(addr) {
+                    log::warn!("failed to leave multicast on {}: {}", interface.name, e);
+                }
+            }
+
+            false
+        });
+
+        self
+    }
+
+    /// Sets whether we should keep listening on the network as interfaces come
+    /// and go, such as when a laptop roams between Wi-Fi networks.
+    ///
+    /// Interfaces are checked every few seconds. The multicast group is joined
+    /// on those that appear, which triggers a query, and left on those that
+    /// disappear. Excluded interfaces stay excluded. This only applies when
+    /// discovering on all interfaces. Defaults to `false`.
+    pub fn follow_interface_changes(mut self, follow: bool) -> Self {
+        self.follow_interface_changes = follow;
+        self
+    }
+
+    /// Gets a sender for our socket, to send queries of our own making.
+    pub fn sender(&self) -> mDNSSender {
+        self.mdns_sender.clone()
+    }
+
+    /// Gets a handle for triggering scans, such as from a refresh button,
+    /// alongside the periodic queries.
+    ///
+    /// Call this before consuming the discovery with one of the `listen`
+    /// methods. Responses to the scans are yielded by the listening stream.
+    pub fn scanner(&self) -> DiscoveryScanner {
+        DiscoveryScanner {
+            sender: self.mdns_sender.clone(),
+        }
+    }
+
+    /// Checks whether a system mDNS daemon, such as avahi or mDNSResponder,
+    /// shares the mDNS port with us.
+    ///
+    /// Depending on the platform, the daemon may be handed some of the
+    /// responses we would otherwise receive, which apps may want to warn
+    /// about. On Linux, any other process bound to port 5353 counts. On macOS,
+    /// mDNSResponder is looked for. Elsewhere, `false` is always returned.
+    pub fn system_daemon_present(&self) -> bool {
+        let mut own_sockets = vec![self.mdns_listener.socket(), self.mdns_sender.socket()];
+        own_sockets.extend(self.mdns_listener.socket_v6());
+        own_sockets.extend(self.mdns_sender.socket_v6());
+
+        crate::daemon::present(&own_sockets)
+    }
+
+    /// Gets the stats of the listener, which stay up to date after this
+    /// discovery starts listening.
+    pub fn stats(&self) -> ListenerStats {
+        self.mdns_listener.stats()
+    }
+
+    /// Gets the effective options of the socket, as reported by the OS.
+    ///
+    /// This helps diagnose systems where shared binding of the mDNS port
+    /// silently fails.
+    pub fn socket_info(&self) -> Result<SocketInfo, Error> {
+        self.mdns_sender.socket_info()
+    }
+
+    /// Waits until the first query has actually been sent.
+    ///
+    /// This must be called before listening. It is useful to avoid races in
+    /// tests, or to only show that we are searching once querying has begun.
+    /// It also completes if discovery is dropped before sending anything.
+    pub fn ready(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.mdns_sender.query_sent()
+    }
+
+    pub fn listen(self) -> impl FusedStream<Item = Result<Response, Error>> {
+        let sender = self.mdns_sender.clone();
+        let first_tick = if self.query_on_listen {
+            Either::Left(stream::once(ready(())))
+        } else {
+            Either::Right(stream::empty())
+        };
+
+        let interval_stream = first_tick
+            .chain(async_std::stream::interval(self.send_request_interval))
+            // I don't like the double clone, I can't find a prettier way to do this
+            .map(move |_| {
+                let mut sender = sender.clone();
+                async_std::task::spawn(async move {
+                    let _ = sender.send_request().await;
+                });
+                StreamResult::Interval
+            });
+
+        self.listen_with_queries(interval_stream)
+    }
+
+    /// Calls `f` with each response until it returns
+    /// [`ControlFlow::Break`], then stops querying and leaves the multicast
+    /// groups, over IPv6 too if we use it.
+    ///
+    /// This lets callers stop discovery on their own conditions, such as
+    /// having found what they need, without cancelling it from outside.
+    /// Returns the first error received, which also stops discovery. A socket
+    /// given to [`from_async_socket`] is left in the groups it was in, as we
+    /// never joined them.
+    pub async fn watch<F>(self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(Response) -> ControlFlow<()>,
+    {
+        let listener = self.mdns_listener.clone();
+        let interfaces = self.interfaces.clone();
+        let interface_addr = self.interface_addr;
+
+        let result = {
+            let stream = self.listen();
+            pin_mut!(stream);
+
+            loop {
+                match stream.next().await {
+                    Some(Ok(response)) => {
+                        if f(response).is_break() {
+                            break Ok(());
+                        }
+                    }
+                    Some(Err(e)) => break Err(e),
+                    None => break Ok(()),
+                }
+            }
+        };
+
+        for interface in &interfaces {
+            if let IpAddr::V4(addr) = interface.ip() {
+                if let Err(e) = listener.leave_interface(addr) {
+                    log::warn!("failed to leave multicast on {}: {}", interface.name, e);
+                }
+            }
+        }
+        if let Some(addr) = interface_addr {
+            if let Err(e) = listener.leave_interface(addr) {
+                log::warn!("failed to leave multicast on {}: {}", addr, e);
+            }
+        }
+        if let Err(e) = listener.leave_v6() {
+            log::warn!("failed to leave IPv6 multicast: {}", e);
+        }
+
+        result
+    }
+
+    /// Listens for the queries other hosts send, without sending any of our
+    /// own.
+    ///
+    /// Each question carries whether it asked for a unicast answer, as a
+    /// responder needs to know.
+    pub fn listen_queries(self) -> impl FusedStream<Item = Result<Query, Error>> {
+        self.mdns_listener.listen_queries()
+    }
+
+    /// Listens for the addresses that discovered services can be connected to.
+    ///
+    /// Each address is only yielded the first time it is learned. Errors are
+    /// skipped.
+    pub fn addresses(self) -> impl FusedStream<Item = SocketAddr> {
+        let mut seen = HashSet::new();
+
+        self.listen()
+            .filter_map(|res| ready(res.ok()))
+            .flat_map(|response| stream::iter(response.socket_addrs()))
+            .filter(move |addr| ready(seen.insert(*addr)))
+    }
+
+    /// Listens for responses, serialized as JSON.
+    ///
+    /// Each item is a single line, suitable for newline-delimited JSON output.
+    /// Errors are skipped.
+    #[cfg(feature = "serde")]
+    pub fn listen_json(self) -> impl FusedStream<Item = String> {
+        self.listen().filter_map(|res| {
+            ready(
+                res.ok()
+                    .and_then(|response| serde_json::to_string(&response).ok()),
+            )
+        })
+    }
+
+    /// Listens for responses, recording how long after our latest query each
+    /// one arrived in a histogram.
+    ///
+    /// The histogram can be snapshotted into percentiles at any time, and is
+    /// logged when the stream ends. Responses without a round-trip time
+    /// aren't recorded.
+    pub fn with_latency_histogram(
+        self,
+    ) -> (
+        impl FusedStream<Item = Result<Response, Error>>,
+        Arc<LatencyHistogram>,
+    ) {
+        let histogram = Arc::new(LatencyHistogram::new());
+        let recorder = histogram.clone();
+        let logger = histogram.clone();
+
+        let stream = self
+            .listen()
+            .inspect(move |res| {
+                if let Some(rtt) = res.as_ref().ok().and_then(|response| response.rtt) {
+                    recorder.record(rtt);
+                }
+            })
+            .map(Some)
+            .chain(stream::once(future::lazy(move |_| {
+                if let Some(snapshot) = logger.snapshot() {
+                    log::debug!("response latencies: {}", snapshot);
+                }
+                None
+            })))
+            .filter_map(ready);
+
+        (stream, histogram)
+    }
+
+    /// Listens for responses, along with the host names their addresses
+    /// resolve to with reverse `PTR` queries.
+    ///
+    /// Addresses without a reverse record are left out of the host names.
+    /// Each address is only looked up once.
+    pub fn with_reverse_names(self) -> impl FusedStream<Item = Result<NamedResponse, Error>> {
+        let known_names: Arc<Mutex<HashMap<IpAddr, Option<String>>>> = Default::default();
+        let inflight = self.inflight.clone();
+        let max_inflight = self.max_inflight;
+        let timeout = self.follow_up_timeout;
+
+        self.listen()
+            .map(move |res| {
+                let known_names = known_names.clone();
+                let inflight = inflight.clone();
+                async move { Ok(name_addresses(res?, &known_names, &inflight, timeout).await) }
+            })
+            .buffer_unordered(max_inflight)
+    }
+
+    /// Listens for responses, attributed to the browsed services they answer.
+    ///
+    /// A response is attributed to each service it has a `PTR` record of,
+    /// with the instance that record points at. Failing that, it is
+    /// attributed to the service one of its record names is an instance of.
+    /// A response answering several services is yielded once for each.
+    pub fn listen_by_service(self) -> impl FusedStream<Item = Result<ServiceResponse, Error>> {
+        let mut service_names = vec![self.service_name.clone()];
+        service_names.extend(self.extra_service_names.iter().cloned());
+
+        self.listen().flat_map(move |res| {
+            let items: Vec<_> = match res {
+                Ok(response) => attribute_services(response, &service_names)
+                    .into_iter()
+                    .map(Ok)
+                    .collect(),
+                Err(e) => vec![Err(e)],
+            };
+
+            stream::iter(items)
+        })
+    }
+
+    /// Listens for changes to the instances of the service.
+    ///
+    /// Each instance is reported when first resolved, whenever its details
+    /// change and when it says goodbye. A change to only its `TXT` record is
+    /// reported as [`DeviceEvent::TxtChanged`]. Responses missing an
+    /// instance's addresses or `TXT` record are taken to leave them as they
+    /// were. See [`max_instances`](Self::max_instances) to bound how many
+    /// instances are tracked.
+    pub fn events(self) -> impl FusedStream<Item = Result<DeviceEvent, Error>> {
+        let mut service_names = vec![self.service_name.clone()];
+        service_names.extend(self.extra_service_names.iter().cloned());
+        let max_instances = self.max_instances;
+        let clock = self.clock.clone();
+        let mut known: HashMap<String, (ServiceInstance, Instant)> = HashMap::new();
+
+        self.listen().flat_map(move |res| {
+            let events: Vec<_> = match res {
+                Ok(response) => device_events(
+                    &mut known,
+                    max_instances,
+                    response,
+                    &service_names,
+                    clock.now(),
+                )
+                .into_iter()
+                .map(Ok)
+                .collect(),
+                Err(e) => vec![Err(e)],
+            };
+
+            stream::iter(events)
+        })
+    }
+
+    /// Listens indefinitely, querying aggressively at first and then settling into
+    /// occasional refreshes.
+    ///
+    /// An initial query is sent straight away, followed by queries spaced 1s, 2s and 4s
+    /// apart as recommended by [RFC 6762 §5.2](https://tools.ietf.org/html/rfc6762#section-5.2).
+    /// After that, a query is sent every `refresh_interval` for as long as the stream lives.
+    pub fn listen_continuous(
+        self,
+        refresh_interval: Duration,
+    ) -> impl FusedStream<Item = Result<Response, Error>> {
+        let schedule = QuerySchedule::new(
+            self.clock.clone(),
+            continuous_query_delays(refresh_interval),
+        );
+        let query_task = QueryTask::spawn(schedule.run(self.mdns_sender.clone()));
+
+        self.listen_with_queries(query_task)
+    }
+
+    /// Listens in consecutive windows, sending a query at the start of each
+    /// and yielding what was gathered during it once it closes.
+    ///
+    /// Windows last for the [per-query window](Self::per_query_window). A
+    /// window without any matching responses yields
+    /// [`ScanOutcome::NoResponders`]. If a
+    /// [session deadline](Self::session_deadline) is set, the window it cuts
+    /// short is yielded and the stream ends.
+    ///
+    /// Errors, such as datagrams that fail to parse, are yielded as they
+    /// happen without closing the window they happen in.
+    pub fn listen_windows(self) -> impl FusedStream<Item = Result<ScanOutcome, Error>> {
+        let window = self.per_query_window;
+        let mut sender = self.mdns_sender.clone();
+        let clock = self.clock.clone();
+        let responses = self.listen_with_queries(stream::pending());
+
+        async_stream::stream! {
+            pin_mut!(responses);
+            let mut ended = false;
+
+            while !ended {
+                let _ = sender.send_request().await;
+
+                let window_end = clock.now() + window;
+                let mut gathered = Vec::new();
+
+                loop {
+                    let remaining = window_end.saturating_duration_since(clock.now());
+
+                    match async_std::future::timeout(remaining, responses.next()).await {
+                        Ok(Some(Ok(response))) => gathered.push(response),
+                        Ok(Some(Err(e))) => yield Err(e),
+                        Ok(None) => {
+                            ended = true;
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+
+                if gathered.is_empty() {
+                    yield Ok(ScanOutcome::NoResponders);
+                } else {
+                    yield Ok(ScanOutcome::Responses(gathered));
+                }
+            }
+        }
+    }
+
+    /// Listens in consecutive windows like
+    /// [`listen_windows`](Self::listen_windows), yielding each window's
+    /// responses in a stable order once it closes.
+    ///
+    /// Responses are sorted by the instance they are about, then by the
+    /// address they were sent from, with those not about any instance last.
+    /// This makes the output deterministic for tests and UIs, at the cost of
+    /// holding responses back until their window closes.
+    pub fn listen_sorted(self) -> impl FusedStream<Item = Result<Response, Error>> {
+        let mut service_names = vec![self.service_name.clone()];
+        service_names.extend(self.extra_service_names.iter().cloned());
+
+        self.listen_windows().flat_map(move |res| {
+            let items: Vec<_> = match res {
+                Ok(ScanOutcome::Responses(mut responses)) => {
+                    responses.sort_by_cached_key(|response| {
+                        let instance = service_names
+                            .iter()
+                            .find_map(|service_name| instance_of(response, service_name));
+                        (instance.is_none(), instance, response.peer_addr)
+                    });
+                    responses.into_iter().map(Ok).collect()
+                }
+                Ok(ScanOutcome::NoResponders) => Vec::new(),
+                Err(e) => vec![Err(e)],
+            };
+
+            stream::iter(items)
+        })
+    }
+
+    /// Sends a burst of queries, then keeps listening without sending any more.
+    ///
+    /// The first query is sent straight away, and the delay before each of the
+    /// following `count - 1` queries doubles, starting at one second. Passive
+    /// listening then catches slow responders and unsolicited announcements
+    /// without generating any further traffic.
+    pub fn burst(self, count: usize) -> impl FusedStream<Item = Result<Response, Error>> {
+        let schedule = QuerySchedule::new(self.clock.clone(), burst_query_delays(count));
+        let query_task = QueryTask::spawn(schedule.run(self.mdns_sender.clone()));
+
+        self.listen_with_queries(query_task)
+    }
+
+    /// Listens until the network goes quiet, ending the stream once no new
+    /// records have arrived for `quiet_period` after the first response, or
+    /// once `max_total` has passed.
+    ///
+    /// Responses that only repeat records already received are still yielded,
+    /// but don't count as activity, so answers to our periodic queries don't
+    /// keep the stream alive. Before the first response, only `max_total`
+    /// applies.
+    pub fn until_quiescent(
+        self,
+        quiet_period: Duration,
+        max_total: Duration,
+    ) -> impl FusedStream<Item = Result<Response, Error>> {
+        let clock = self.clock.clone();
+        let stream = self.session_deadline(max_total).listen();
+
+        async_stream::stream! {
+            pin_mut!(stream);
+            let mut seen: HashSet<RecordKey> = HashSet::new();
+            let mut quiet_at: Option<Instant> = None;
+
+            loop {
+                let next = match quiet_at {
+                    Some(quiet_at) => {
+                        let remaining = quiet_at.saturating_duration_since(clock.now());
+                        match async_std::future::timeout(remaining, stream.next()).await {
+                            Ok(next) => next,
+                            Err(_) => break,
+                        }
+                    }
+                    None => stream.next().await,
+                };
+
+                match next {
+                    Some(Ok(response)) => {
+                        let mut active = false;
+                        for record in response.records() {
+                            active |= seen.insert(record.key());
+                        }
+                        if active {
+                            quiet_at = Some(clock.now() + quiet_period);
+                        }
+
+                        yield Ok(response);
+                    }
+                    Some(Err(e)) => yield Err(e),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Listens until every expected instance has responded or `timeout` elapses.
+    ///
+    /// A response counts towards a name if it has a record of that name, or a
+    /// `PTR` record pointing at it. Returns the responses that matched any of
+    /// the names, along with the names nothing was heard from.
+    pub async fn poll_expected(
+        self,
+        names: &[String],
+        timeout: Duration,
+    ) -> Result<(Vec<Response>, Vec<String>), Error> {
+        let stream = self.listen();
+        pin_mut!(stream);
+
+        let mut responses = Vec::new();
+        let mut missing = names.to_vec();
+
+        let process = async {
+            while let Some(response) = stream.next().await {
+                let response = response?;
+                let expected = missing.len();
+                missing.retain(|name| !mentions(&response, name));
+
+                if missing.len() < expected {
+                    responses.push(response);
+                }
+
+                if missing.is_empty() {
+                    break;
+                }
+            }
+
+            Ok::<_, Error>(())
+        };
+
+        // Running out of time is expected, as that's how missing instances are found.
+        if let Ok(result) = async_std::future::timeout(timeout, process).await {
+            result?;
+        }
+
+        Ok((responses, missing))
+    }
+
+    /// Waits for the first fully resolved instance of the service, with its
+    /// `SRV` record and addresses, for at most `timeout`.
+    ///
+    /// Queries are sent as by [`listen_continuous`](Self::listen_continuous),
+    /// and instances are resolved as if [`auto_resolve`](Self::auto_resolve)
+    /// were set. If none is complete by the time `timeout` elapses, the first
+    /// instance whose `SRV` record arrived without addresses is returned
+    /// instead, or `None` if there wasn't one.
+    pub async fn best_within(
+        mut self,
+        timeout: Duration,
+    ) -> Result<Option<ServiceInstance>, Error> {
+        let mut service_names = vec![self.service_name.clone()];
+        service_names.extend(self.extra_service_names.iter().cloned());
+
+        self.auto_resolve = true;
+        let refresh_interval = self.send_request_interval;
+        let stream = self.listen_continuous(refresh_interval);
+        pin_mut!(stream);
+
+        let mut partial = None;
+
+        let process = async {
+            while let Some(response) = stream.next().await {
+                for found in attribute_services(response?, &service_names) {
+                    let instance = found.instance.as_ref().and_then(|instance_name| {
+                        ServiceInstance::from_response(instance_name, &found.response)
+                    });
+
+                    match instance {
+                        Some(instance) if !instance.addresses.is_empty() => {
+                            return Ok(Some(instance))
+                        }
+                        Some(instance) if partial.is_none() => partial = Some(instance),
+                        _ => {}
+                    }
+                }
+            }
+
+            Ok::<_, Error>(None)
+        };
+
+        match async_std::future::timeout(timeout, process).await {
+            Ok(Ok(Some(instance))) => Ok(Some(instance)),
+            Ok(Err(e)) => Err(e),
+            Ok(Ok(None)) | Err(_) => Ok(partial),
+        }
+    }
+
+    /// Listens for `timeout`, yielding responses as they arrive, and gives the
+    /// instances found once the time is up.
+    ///
+    /// Both are fed by a single scan running in the background, with
+    /// [`auto_resolve`](Self::auto_resolve) enabled, so the future resolves at
+    /// the timeout whether or not the stream is read. The instances are
+    /// deduplicated by name, keeping the latest version of each, and only
+    /// include those whose `SRV` record was received.
+    pub fn listen_with_summary(
+        mut self,
+        timeout: Duration,
+    ) -> (
+        impl FusedStream<Item = Result<Response, Error>>,
+        impl Future<Output = Vec<ServiceInstance>>,
+    ) {
+        let mut service_names = vec![self.service_name.clone()];
+        service_names.extend(self.extra_service_names.iter().cloned());
+        let (responses_tx, responses_rx) = async_std::channel::unbounded();
+
+        self.auto_resolve = true;
+        let stream = self.listen();
+
+        let summary = async_std::task::spawn(async move {
+            pin_mut!(stream);
+            let mut instances: Vec<ServiceInstance> = Vec::new();
+
+            let scan = async {
+                while let Some(res) = stream.next().await {
+                    if let Ok(ref response) = res {
+                        for found in attribute_services(response.clone(), &service_names) {
+                            let instance = found.instance.as_ref().and_then(|instance_name| {
+                                ServiceInstance::from_response(instance_name, &found.response)
+                            });
+                            let instance = match instance {
+                                Some(instance) => instance,
+                                None => continue,
+                            };
+
+                            match instances
+                                .iter_mut()
+                                .find(|known| known.name == instance.name)
+                            {
+                                Some(known) => *known = instance,
+                                None => instances.push(instance),
+                            }
+                        }
+                    }
+
+                    // The stream may have been dropped by a caller only
+                    // interested in the summary.
+                    let _ = responses_tx.send(res).await;
+                }
+            };
+
+            let _ = async_std::future::timeout(timeout, scan).await;
+            instances
+        });
+
+        (responses_rx, summary)
+    }
+
+    /// Listens for responses while `query_stream` takes care of sending our queries.
+    fn listen_with_queries<Q>(
+        self,
+        query_stream: Q,
+    ) -> impl FusedStream<Item = Result<Response, Error>>
+    where
+        Q: Stream<Item = StreamResult>,
+    {
+        let ignore_empty = self.ignore_empty;
+        let match_all = self.match_all;
+        let filters = self.filters;
+        let min_confirmations = self.min_confirmations;
+        let local_subnets = if self.same_subnet_only {
+            match if_addrs::get_if_addrs() {
+                Ok(interfaces) => Some(interfaces),
+                Err(e) => {
+                    log::warn!(
+                        "failed to list interfaces, so not filtering by subnet: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let mut confirmations: HashMap<String, usize> = HashMap::new();
+        let address_family = self.address_family;
+        let query_type = self.query_type;
+        let mut service_names = vec![self.service_name];
+        service_names.extend(self.extra_service_names);
+        let confirmed_names = service_names.clone();
+        let auto_resolve = self.auto_resolve;
+        let combined_query = self.combined_query;
+        let follow_up_timeout = self.follow_up_timeout;
+        let resolve_sender = self.mdns_sender.clone();
+        let inflight = self.inflight.clone();
+        let mut unresolved = HashMap::new();
+
+        let interface_stream = if self.follow_interface_changes && self.all_interfaces {
+            let listener = self.mdns_listener.clone();
+            let sender = self.mdns_sender.clone();
+            let excluded = self.excluded_interfaces;
+            let include_loopback = self.include_loopback;
+            let mut joined = self.interfaces;
+
+            Either::Left(
+                async_std::stream::interval(INTERFACE_POLL_INTERVAL).map(move |_| {
+                    if follow_interfaces(&listener, &mut joined, &excluded, include_loopback) {
+                        let mut sender = sender.clone();
+                        async_std::task::spawn(async move {
+                            let _ = sender.send_request().await;
+                        });
+                    }
+
+                    StreamResult::Interval
+                }),
+            )
+        } else {
+            Either::Right(stream::pending())
+        };
+
+        let responses = self.mdns_listener.listen();
+        let responses = match self.correlation_window {
+            Some(window) => {
+                Either::Left(correlate_datagrams(responses, window, self.clock.clone()))
+            }
+            None => Either::Right(responses),
+        };
+        let response_stream = match self.completion_window {
+            Some(window) => Either::Left(complete_within(
+                responses,
+                window,
+                service_names.clone(),
+                self.clock.clone(),
+            )),
+            None => Either::Right(responses),
+        }
+        .map(StreamResult::Response)
+        .chain(stream::once(ready(StreamResult::Ended)));
+
+        // Woken whenever a response is cached, as it may be due for a refresh
+        // sooner than the records already cached.
+        let (refresh_wake, refresh_woken) = async_std::channel::bounded::<()>(1);
+        let refresh_stream = match self.cache.clone() {
+            Some(cache) => {
+                let sender = self.mdns_sender.clone();
+
+                Either::Left(async_stream::stream! {
+                    loop {
+                        let (questions, until_next) = {
+                            let mut cache = cache.lock().unwrap();
+                            cache.expire();
+                            (cache.take_due_refreshes(), cache.until_next_event())
+                        };
+
+                        for (name, query_type) in questions {
+                            let mut sender = sender.clone();
+                            async_std::task::spawn(async move {
+                                let _ = sender.send_query_for(&name, query_type).await;
+                            });
+                        }
+
+                        let next_event = match until_next {
+                            Some(duration) => Either::Left(async_std::task::sleep(duration)),
+                            None => Either::Right(future::pending()),
+                        };
+                        pin_mut!(next_event);
+                        future::select(next_event, refresh_woken.recv()).await;
+
+                        yield StreamResult::Interval;
+                    }
+                })
+            }
+            None => Either::Right(stream::pending()),
+        };
+        let expire_stream = match self.store.clone() {
+            Some(store) => Either::Left(async_std::stream::interval(STORE_EXPIRE_INTERVAL).map(
+                move |_| {
+                    let store = store.clone();
+                    async_std::task::spawn(async move {
+                        if let Err(e) = store.expire().await {
+                            log::warn!("failed to expire stored records: {:?}", e);
+                        }
+                    });
+
+                    StreamResult::Interval
+                },
+            )),
+            None => Either::Right(stream::pending()),
+        };
+        let cache = self.cache;
+        let store = self.store;
+        let session_end = match self.session_deadline {
+            Some(deadline) => Either::Left(async_std::task::sleep(deadline)),
+            None => Either::Right(future::pending()),
+        };
+
+        let stream = select(
+            response_stream,
+            select(
+                query_stream,
+                select(refresh_stream, select(expire_stream, interface_stream)),
+            ),
+        );
+        stream
+            .take_while(|stream_result| ready(!matches!(stream_result, StreamResult::Ended)))
+            .filter_map(|stream_result| {
+                async {
+                    match stream_result {
+                        StreamResult::Interval | StreamResult::Ended => None,
+                        StreamResult::Response(res) => Some(res),
+                    }
+                }
+            })
+            .inspect(move |res| {
+                if let (Some(cache), Ok(response)) = (&cache, res) {
+                    cache.lock().unwrap().insert(response);
+                    let _ = refresh_wake.try_send(());
+                }
+            })
+            .filter_map(move |res| {
+                ready(match res {
+                    Ok(response) => {
+                        if let Some(resolved) = complete_instance(&mut unresolved, &response) {
+                            Some(Ok(resolved))
+                        } else if (!response.is_empty() || !ignore_empty)
+                            && (match_all
+                                || service_names
+                                    .iter()
+                                    .any(|name| response.answers_query(name, query_type)))
+                        {
+                            if auto_resolve {
+                                for service_name in service_names.iter() {
+                                    resolve_bare_instances(
+                                        &mut unresolved,
+                                        &response,
+                                        service_name,
+                                        &resolve_sender,
+                                        &inflight,
+                                        combined_query,
+                                        follow_up_timeout,
+                                    );
+                                }
+                            }
+
+                            Some(Ok(response))
+                        } else {
+                            None
+                        }
+                    }
+                    Err(e) => Some(Err(e)),
+                })
+            })
+            .map(move |res| {
+                res.map(|mut response| {
+                    if address_family != AddressFamily::Both {
+                        response.retain_records(|record| address_family.keeps(record));
+                    }
+
+                    response
+                })
+            })
+            .filter(move |res| {
+                ready(match (res, &local_subnets) {
+                    (Ok(response), Some(interfaces)) => response
+                        .peer_addr
+                        .is_none_or(|peer_addr| on_local_subnet(peer_addr.ip(), interfaces)),
+                    _ => true,
+                })
+            })
+            .filter(move |res| {
+                ready(match res {
+                    Ok(response) => filters.iter().all(|predicate| predicate(response)),
+                    Err(_) => true,
+                })
+            })
+            .filter(move |res| {
+                ready(match res {
+                    Ok(response) if min_confirmations > 1 => {
+                        let instance = confirmed_names
+                            .iter()
+                            .find_map(|service_name| instance_of(response, service_name));
+
+                        match instance {
+                            Some(instance) => {
+                                let seen = confirmations.entry(instance).or_insert(0);
+                                *seen += 1;
+                                *seen >= min_confirmations
+                            }
+                            None => true,
+                        }
+                    }
+                    _ => true,
+                })
+            })
+            .then(move |res| {
+                let store = store.clone();
+
+                async move {
+                    if let (Some(store), Ok(response)) = (store, &res) {
+                        if let Err(e) = store.insert(response).await {
+                            log::warn!("failed to store response: {:?}", e);
+                        }
+                    }
+
+                    res
+                }
+            })
+            .take_until(session_end)
+    }
+}
+
+/// Works out when to send our queries, each one after the next of `delays`,
+/// timed by a clock.
+struct QuerySchedule<I> {
+    clock: Arc<dyn Clock>,
+    delays: I,
+    /// When the next query is due, or `None` once there are none left.
+    next_at: Option<Instant>,
+}
+
+impl<I> QuerySchedule<I>
+where
+    I: Iterator<Item = Duration>,
+{
+    fn new(clock: Arc<dyn Clock>, mut delays: I) -> Self {
+        let next_at = delays.next().map(|delay| clock.now() + delay);
+
+        QuerySchedule {
+            clock,
+            delays,
+            next_at,
+        }
+    }
+
+    /// Gets how long until the next query is due, or `None` once there are
+    /// none left.
+    fn until_next(&self) -> Option<Duration> {
+        let next_at = self.next_at?;
+        Some(next_at.saturating_duration_since(self.clock.now()))
+    }
+
+    /// Notes that the query that was due has been sent, scheduling the next.
+    fn sent(&mut self) {
+        let now = self.clock.now();
+        self.next_at = self.delays.next().map(|delay| now + delay);
+    }
+
+    /// Sends the scheduled queries through `sender`.
+    async fn run(mut self, mut sender: mDNSSender) {
+        while let Some(wait) = self.until_next() {
+            async_std::task::sleep(wait).await;
+            let _ = sender.send_request().await;
+            self.sent();
+        }
+    }
+}
+
+/// The delays before each query of continuous discovery, the first of which
+/// is sent straight away.
+fn continuous_query_delays(refresh_interval: Duration) -> impl Iterator<Item = Duration> {
+    iter::once(Duration::ZERO)
+        .chain(INITIAL_QUERY_DELAYS.iter().copied())
+        .chain(iter::repeat(refresh_interval))
+}
+
+/// The delays before each of the `count` queries of a burst. The first is
+/// sent straight away, and the delays after it double from one second.
+fn burst_query_delays(count: usize) -> impl Iterator<Item = Duration> {
+    let doubling = iter::successors(Some(Duration::from_secs(1)), |delay| Some(*delay * 2));
+    iter::once(Duration::ZERO).chain(doubling).take(count)
+}
+
+/// A spawned task that sends our queries.
+///
+/// The task is stopped when this is dropped. It is a stream that never yields,
+/// so that it can be selected alongside the responses and live exactly as long.
+struct QueryTask(AbortHandle);
+
+impl QueryTask {
+    fn spawn<F>(task: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (task, abort_handle) = abortable(task);
+        async_std::task::spawn(task);
+        QueryTask(abort_handle)
+    }
+}
+
+impl Stream for QueryTask {
+    type Item = StreamResult;
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Pending
+    }
+}
+
+impl Drop for QueryTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+enum StreamResult {
+    Interval,
+    Response(Result<Response, Error>),
+    /// The listener has stopped, so there will be no more responses.
+    Ended,
+}
+
+/// Joins the multicast group on new interfaces and leaves those that are gone.
+///
+/// Returns whether any interfaces were joined.
+fn follow_interfaces(
+    listener: &mDNSListener,
+    joined: &mut Vec<Interface>,
+    excluded: &[Interface],
+    include_loopback: bool,
+) -> bool {
+    let current = match multicast_interfaces(include_loopback) {
+        Ok(current) => current,
+        Err(e) => {
+            log::warn!("failed to list interfaces: {}", e);
+            return false;
+        }
+    };
+
+    // An interface whose address changed is left and joined again.
+    let is_listed = |list: &[Interface], interface: &Interface| {
+        list.iter()
+            .any(|i| is_same_interface(i, interface) && i.ip() == interface.ip())
+    };
+
+    joined.retain(|interface| {
+        if is_listed(&current, interface) {
+            return true;
+        }
+
+        if let IpAddr::V4(addr) = interface.ip() {
+            // This usually fails as the interface is already gone, taking the
+            // membership with it.
+            let _ = listener.leave_interface(addr);
+        }
+
+        log::debug!("left multicast on departed interface {}", interface.name);
+        false
+    });
+
+    let mut joined_any = false;
+
+    for interface in current {
+        if is_listed(joined, &interface)
+            || excluded.iter().any(|i| is_same_interface(i, &interface))
+        {
+            continue;
+        }
+
+        if let IpAddr::V4(addr) = interface.ip() {
+            match listener.join_interface(addr) {
+                Ok(()) => {
+                    log::debug!("joined multicast on new interface {}", interface.name);
+                    joined.push(interface);
+                    joined_any = true;
+                }
+                Err(e) => log::warn!("failed to join multicast on {}: {}", interface.name, e),
+            }
+        }
+    }
+
+    joined_any
+}
+
+/// Reverse-resolves the addresses of a response, remembering the host names
+/// found for each address in `known_names`.
+///
+/// A lookup that takes longer than `timeout` is skipped without being
+/// remembered, so that it is tried again for a later response.
+async fn name_addresses(
+    response: Response,
+    known_names: &Mutex<HashMap<IpAddr, Option<String>>>,
+    inflight: &Semaphore,
+    timeout: Duration,
+) -> NamedResponse {
+    let mut addrs: Vec<IpAddr> = Vec::new();
+
+    for addr in response.ip_addrs() {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    let lookups = addrs.into_iter().map(|addr| async move {
+        let known = known_names.lock().unwrap().get(&addr).cloned();
+
+        let hostname = match known {
+            Some(hostname) => hostname,
+            None => {
+                let _permit = inflight.acquire().await;
+                let lookup = crate::resolve::reverse(addr, timeout);
+                match async_std::future::timeout(timeout, lookup).await {
+                    Ok(result) => {
+                        let hostname = result.ok().flatten();
+                        known_names.lock().unwrap().insert(addr, hostname.clone());
+                        hostname
+                    }
+                    Err(_) => {
+                        log::debug!("skipping reverse lookup of {} after timing out", addr);
+                        None
+                    }
+                }
+            }
+        };
+
+        hostname.map(|hostname| (addr, hostname))
+    });
+
+    let hostnames = join_all(lookups).await.into_iter().flatten().collect();
+
+    NamedResponse {
+        response,
+        hostnames,
+    }
+}
+
+/// Queries every instance a response names without including its `SRV`
+/// record, keeping the response so that it can be completed later.
+///
+/// Each query holds one of the `inflight` permits until it is answered or
+/// `timeout` passes, and is skipped if it can't be sent within `timeout`. A
+/// `combined` query also asks for the service again.
+fn resolve_bare_instances(
+    unresolved: &mut HashMap<String, (Response, AbortHandle)>,
+    response: &Response,
+    service_name: &str,
+    sender: &mDNSSender,
+    inflight: &Arc<Semaphore>,
+    combined: bool,
+    timeout: Duration,
+) {
+    for record in response
+        .answers
+        .iter()
+        .filter(|record| record.name == service_name)
+    {
+        let instance_name = match record.kind {
+            RecordKind::PTR(ref instance_name) => instance_name,
+            _ => continue,
+        };
+
+        if has_srv(response, instance_name) || unresolved.contains_key(instance_name) {
+            continue;
+        }
+
+        let (answered, answer_handle) = abortable(async_std::task::sleep(timeout));
+        unresolved.insert(instance_name.clone(), (response.clone(), answer_handle));
+
+        let mut sender = sender.clone();
+        let instance_name = instance_name.clone();
+        let service_name = service_name.to_owned();
+        let inflight = inflight.clone();
+        async_std::task::spawn(async move {
+            let _permit = inflight.acquire_arc().await;
+            let send = async {
+                if combined {
+                    let questions = [
+                        (service_name.as_str(), dns_parser::QueryType::PTR),
+                        (instance_name.as_str(), dns_parser::QueryType::SRV),
+                        (instance_name.as_str(), dns_parser::QueryType::TXT),
+                    ];
+                    sender.send_questions(&questions).await
+                } else {
+                    sender
+                        .send_query_for(&instance_name, dns_parser::QueryType::All)
+                        .await
+                }
+            };
+            if async_std::future::timeout(timeout, send).await.is_err() {
+                log::debug!(
+                    "skipping follow-up query for {} after timing out",
+                    instance_name
+                );
+                return;
+            }
+            let _ = answered.await;
+        });
+    }
+}
+
+/// Merges the responses each responder sends within `window` of its first,
+/// yielding them once the window closes.
+///
+/// Responses without a sender address are yielded straight away.
+fn correlate_datagrams<S>(
+    responses: S,
+    window: Duration,
+    clock: Arc<dyn Clock>,
+) -> impl Stream<Item = Result<Response, Error>>
+where
+    S: Stream<Item = Result<Response, Error>>,
+{
+    async_stream::stream! {
+        pin_mut!(responses);
+        let mut pending: Vec<(Instant, Response)> = Vec::new();
+
+        loop {
+            let next = match pending.iter().map(|(window_end, _)| *window_end).min() {
+                Some(window_end) => {
+                    let remaining = window_end.saturating_duration_since(clock.now());
+                    async_std::future::timeout(remaining, responses.next()).await.ok()
+                }
+                None => Some(responses.next().await),
+            };
+
+            match next {
+                Some(Some(Ok(response))) => {
+                    let peer_addr = match response.peer_addr {
+                        Some(peer_addr) => peer_addr,
+                        None => {
+                            yield Ok(response);
+                            continue;
+                        }
+                    };
+
+                    match pending
+                        .iter_mut()
+                        .find(|(_, gathered)| gathered.peer_addr == Some(peer_addr))
+                    {
+                        Some((_, gathered)) => merge_response(gathered, response),
+                        None => pending.push((clock.now() + window, response)),
+                    }
+                }
+                Some(Some(Err(e))) => yield Err(e),
+                Some(None) => {
+                    for (_, gathered) in pending.drain(..) {
+                        yield Ok(gathered);
+                    }
+                    break;
+                }
+                None => {
+                    let now = clock.now();
+                    let (closed, open) = pending
+                        .drain(..)
+                        .partition(|(window_end, _)| *window_end <= now);
+                    pending = open;
+
+                    for (_, gathered) in closed {
+                        yield Ok(gathered);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Merges the responses about each instance received within `window` of the
+/// first that named it, yielding them once the window closes.
+///
+/// Responses that don't name an instance of `service_names` are yielded
+/// straight away.
+fn complete_within<S>(
+    responses: S,
+    window: Duration,
+    service_names: Vec<String>,
+    clock: Arc<dyn Clock>,
+) -> impl Stream<Item = Result<Response, Error>>
+where
+    S: Stream<Item = Result<Response, Error>>,
+{
+    async_stream::stream! {
+        pin_mut!(responses);
+        let mut pending: Vec<(Instant, Vec<String>, Response)> = Vec::new();
+
+        loop {
+            let next = match pending.iter().map(|(window_end, ..)| *window_end).min() {
+                Some(window_end) => {
+                    let remaining = window_end.saturating_duration_since(clock.now());
+                    async_std::future::timeout(remaining, responses.next()).await.ok()
+                }
+                None => Some(responses.next().await),
+            };
+
+            match next {
+                Some(Some(Ok(response))) => {
+                    let names = completion_names(&response, &service_names);
+
+                    match pending
+                        .iter_mut()
+                        .find(|(_, gathered_names, _)| {
+                            gathered_names.iter().any(|name| mentions(&response, name))
+                        })
+                    {
+                        Some((_, gathered_names, gathered)) => {
+                            for name in names {
+                                if !gathered_names.contains(&name) {
+                                    gathered_names.push(name);
+                                }
+                            }
+                            merge_response(gathered, response);
+                        }
+                        None if names.is_empty() => yield Ok(response),
+                        None => pending.push((clock.now() + window, names, response)),
+                    }
+                }
+                Some(Some(Err(e))) => yield Err(e),
+                Some(None) => {
+                    for (.., gathered) in pending.drain(..) {
+                        yield Ok(gathered);
+                    }
+                    break;
+                }
+                None => {
+                    let now = clock.now();
+                    let (closed, open) = pending
+                        .drain(..)
+                        .partition(|(window_end, ..)| *window_end <= now);
+                    pending = open;
+
+                    for (.., gathered) in closed {
+                        yield Ok(gathered);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Lists the names that records completing the instances in a response are
+/// about: the instances it answers `service_names` with, and the instances
+/// and hosts of its `SRV` records.
+fn completion_names(response: &Response, service_names: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+
+    for record in response.records() {
+        let found = match record.kind {
+            RecordKind::PTR(ref instance_name) if service_names.contains(&record.name) => {
+                vec![instance_name]
+            }
+            RecordKind::SRV { ref target, .. } => vec![&record.name, target],
+            _ => continue,
+        };
+
+        for name in found {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+
+    names
+}
+
+/// Adds the records of `response` that `gathered` doesn't already have.
+fn merge_response(gathered: &mut Response, response: Response) {
+    let sections = [
+        (&mut gathered.answers, response.answers),
+        (&mut gathered.nameservers, response.nameservers),
+        (&mut gathered.additional, response.additional),
+    ];
+
+    for (section, records) in sections {
+        for record in records {
+            if !section.contains(&record) {
+                section.push(record);
+            }
+        }
+    }
+}
+
+/// Merges a response holding the `SRV` record of an unresolved instance into
+/// the response that named it.
+fn complete_instance(
+    unresolved: &mut HashMap<String, (Response, AbortHandle)>,
+    response: &Response,
+) -> Option<Response> {
+    let instance_name = unresolved
+        .keys()
+        .find(|instance_name| has_srv(response, instance_name))?
+        .clone();
+
+    let (mut resolved, answer_handle) = unresolved.remove(&instance_name)?;
+    answer_handle.abort();
+    resolved.additional.extend(response.records().cloned());
+
+    Some(resolved)
+}
+
+/// Works out how the instances a response describes have changed since they
+/// were last seen, keeping `known` up to date along with when each was last
+/// seen.
+fn device_events(
+    known: &mut HashMap<String, (ServiceInstance, Instant)>,
+    max_instances: Option<usize>,
+    response: Response,
+    service_names: &[String],
+    now: Instant,
+) -> Vec<DeviceEvent> {
+    let mut events = Vec::new();
+
+    for record in response.records().filter(|record| record.ttl == 0) {
+        // A goodbye is usually the service's `PTR` record of the instance,
+        // though some responders send the instance's `SRV` record instead.
+        let instance_name = match record.kind {
+            RecordKind::PTR(ref instance_name) if service_names.contains(&record.name) => {
+                instance_name
+            }
+            RecordKind::SRV { .. } => &record.name,
+            _ => continue,
+        };
+
+        if known.remove(instance_name).is_some() {
+            events.push(DeviceEvent::Removed(instance_name.clone()));
+        }
+    }
+
+    for found in attribute_services(response, service_names) {
+        let instance_name = match found.instance {
+            Some(ref instance_name) => instance_name,
+            None => continue,
+        };
+
+        let mut instance = match ServiceInstance::from_response(instance_name, &found.response) {
+            Some(instance) => instance,
+            None => continue,
+        };
+
+        if events.contains(&DeviceEvent::Removed(instance.name.clone())) {
+            continue;
+        }
+
+        let old = match known.get_mut(&instance.name) {
+            Some((old, last_seen)) => {
+                *last_seen = now;
+                if instance.addresses.is_empty() {
+                    instance.addresses = old.addresses.clone();
+                }
+                if instance.txt.is_empty() {
+                    instance.txt = old.txt.clone();
+                }
+                Some(&*old)
+            }
+            None => None,
+        };
+
+        let event = match DeviceEvent::between(old, &instance) {
+            Some(event) => event,
+            None => continue,
+        };
+
+        if let (DeviceEvent::Added(..), Some(max_instances)) = (&event, max_instances) {
+            while known.len() >= max_instances {
+                let least_recent = known
+                    .iter()
+                    .min_by_key(|(_, &(_, last_seen))| last_seen)
+                    .map(|(name, _)| name.clone())
+                    .unwrap();
+                known.remove(&least_recent);
+                events.push(DeviceEvent::Removed(least_recent));
+            }
+        }
+
+        events.push(event);
+        known.insert(instance.name.clone(), (instance, now));
+    }
+
+    events
+}
+
+/// Works out which of `service_names` a response answers, and the instance
+/// it answers with.
+fn attribute_services(response: Response, service_names: &[String]) -> Vec<ServiceResponse> {
+    let mut matches: Vec<(String, Option<String>)> = Vec::new();
+
+    for service_name in service_names {
+        if let Some(instance) = instance_of(&response, service_name) {
+            matches.push((service_name.clone(), Some(instance)));
+        } else if response
+            .answers
+            .iter()
+            .any(|record| record.name == *service_name)
+        {
+            matches.push((service_name.clone(), None));
+        }
+    }
+
+    matches
+        .into_iter()
+        .map(|(service_type, instance)| ServiceResponse {
+            service_type,
+            instance,
+            response: response.clone(),
+        })
+        .collect()
+}
+
+/// Finds the instance of a service that a response is about, from its `PTR`
+/// record of the service or, failing that, a record named as an instance.
+fn instance_of(response: &Response, service_name: &str) -> Option<String> {
+    let instance = response
+        .answers
+        .iter()
+        .find_map(|record| match record.kind {
+            RecordKind::PTR(ref instance) if record.name == service_name => Some(instance.clone()),
+            _ => None,
+        });
+
+    instance.or_else(|| {
+        let suffix = format!(".{}", service_name);
+        response
+            .records()
+            .find(|record| record.name.ends_with(&suffix))
+            .map(|record| record.name.clone())
+    })
+}
+
+/// Checks whether an address is on the subnet of any of `interfaces`.
+fn on_local_subnet(addr: IpAddr, interfaces: &[Interface]) -> bool {
+    interfaces
+        .iter()
+        .any(|interface| match (&interface.addr, addr) {
+            (IfAddr::V4(local), IpAddr::V4(addr)) => {
+                let mask = u32::from(local.netmask);
+                u32::from(local.ip) & mask == u32::from(addr) & mask
+            }
+            (IfAddr::V6(local), IpAddr::V6(addr)) => {
+                let mask = u128::from(local.netmask);
+                u128::from(local.ip) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        })
+}
+
+fn has_srv(response: &Response, instance_name: &str) -> bool {
+    response.records().any(|record| {
+        record.name == instance_name && matches!(record.kind, RecordKind::SRV { .. })
+    })
+}
+
+/// Checks whether a response has a record of `name`, or points at it.
+fn mentions(response: &Response, name: &str) -> bool {
+    response.records().any(|record| {
+        record.name == name || matches!(record.kind, RecordKind::PTR(ref target) if target == name)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, Section};
+
+    const SERVICE_NAME: &str = "_http._tcp.local";
+    const INSTANCE_NAME: &str = "printer._http._tcp.local";
+
+    fn record(name: &str, ttl: u32, kind: RecordKind) -> Record {
+        Record {
+            name: name.to_owned(),
+            class: dns_parser::Class::IN,
+            ttl,
+            kind,
+            cache_flush: false,
+            section: Section::Answer,
+        }
+    }
+
+    fn response(answers: Vec<Record>) -> Response {
+        Response {
+            answers,
+            nameservers: Vec::new(),
+            additional: Vec::new(),
+            rtt: None,
+            local_addr: None,
+            interface_index: None,
+            peer_addr: None,
+        }
+    }
+
+    /// A response announcing the instance with a `TTL` of `ttl`.
+    fn announcement(ttl: u32) -> Response {
+        response(vec![
+            record(SERVICE_NAME, ttl, RecordKind::PTR(INSTANCE_NAME.to_owned())),
+            record(
+                INSTANCE_NAME,
+                ttl,
+                RecordKind::SRV {
+                    priority: 0,
+                    weight: 0,
+                    port: 80,
+                    target: "printer.local".to_owned(),
+                },
+            ),
+            record(
+                "printer.local",
+                ttl,
+                RecordKind::A([192, 168, 1, 20].into()),
+            ),
+        ])
+    }
+
+    fn events_of(
+        known: &mut HashMap<String, (ServiceInstance, Instant)>,
+        response: Response,
+    ) -> Vec<DeviceEvent> {
+        device_events(
+            known,
+            None,
+            response,
+            &[SERVICE_NAME.to_owned()],
+            Instant::now(),
+        )
+    }
+
+    #[test]
+    fn ptr_goodbye_removes_instance() {
+        let mut known = HashMap::new();
+        let added = events_of(&mut known, announcement(120));
+        assert!(matches!(added[..], [DeviceEvent::Added(..)]));
+
+        let goodbye = response(vec![record(
+            SERVICE_NAME,
+            0,
+            RecordKind::PTR(INSTANCE_NAME.to_owned()),
+        )]);
+        assert_eq!(
+            events_of(&mut known, goodbye),
+            [DeviceEvent::Removed(INSTANCE_NAME.to_owned())]
+        );
+        assert!(known.is_empty());
+    }
+
+    #[test]
+    fn srv_goodbye_removes_instance() {
+        let mut known = HashMap::new();
+        events_of(&mut known, announcement(120));
+
+        assert_eq!(
+            events_of(&mut known, announcement(0)),
+            [DeviceEvent::Removed(INSTANCE_NAME.to_owned())]
+        );
+        assert!(known.is_empty());
+    }
+
+    #[test]
+    fn goodbye_of_another_service_is_ignored() {
+        let mut known = HashMap::new();
+        events_of(&mut known, announcement(120));
+
+        let goodbye = response(vec![record(
+            "_ipp._tcp.local",
+            0,
+            RecordKind::PTR(INSTANCE_NAME.to_owned()),
+        )]);
+        assert!(events_of(&mut known, goodbye).is_empty());
+        assert_eq!(known.len(), 1);
+    }
+
+    /// Sends every query of a schedule as soon as it is due, returning the
+    /// delay before each one.
+    fn delays<I>(mut schedule: QuerySchedule<I>, clock: &ManualClock, limit: usize) -> Vec<u64>
+    where
+        I: Iterator<Item = Duration>,
+    {
+        let mut delays = Vec::new();
+
+        while let Some(wait) = schedule.until_next() {
+            if delays.len() == limit {
+                break;
+            }
+
+            clock.advance(wait);
+            assert_eq!(schedule.until_next(), Some(Duration::ZERO));
+            schedule.sent();
+            delays.push(wait.as_secs());
+        }
+
+        delays
+    }
+
+    #[test]
+    fn continuous_queries_back_off_to_the_refresh_interval() {
+        let clock = ManualClock::new();
+        let refresh_interval = Duration::from_secs(60);
+        let schedule = QuerySchedule::new(
+            Arc::new(clock.clone()),
+            continuous_query_delays(refresh_interval),
+        );
+
+        assert_eq!(delays(schedule, &clock, 7), [0, 1, 2, 4, 60, 60, 60]);
+    }
+
+    #[test]
+    fn burst_delays_double_then_stop() {
+        let clock = ManualClock::new();
+        let schedule = QuerySchedule::new(Arc::new(clock.clone()), burst_query_delays(4));
+
+        assert_eq!(delays(schedule, &clock, 10), [0, 1, 2, 4]);
+    }
+
+    #[test]
+    fn empty_burst_sends_nothing() {
+        let clock = ManualClock::new();
+        let schedule = QuerySchedule::new(Arc::new(clock.clone()), burst_query_delays(0));
+
+        assert_eq!(schedule.until_next(), None);
+    }
+
+    #[test]
+    fn schedule_waits_for_the_clock() {
+        let clock = ManualClock::new();
+        let mut schedule = QuerySchedule::new(Arc::new(clock.clone()), burst_query_delays(2));
+        schedule.sent();
+
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(schedule.until_next(), Some(Duration::from_millis(600)));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(schedule.until_next(), Some(Duration::ZERO));
+    }
 }
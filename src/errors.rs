@@ -1,3 +1,6 @@
+// err-derive expands its impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use err_derive::Error;
 
 #[derive(Debug, Error)]
@@ -6,6 +9,36 @@ pub enum Error {
     Io(#[error(source)] std::io::Error),
     #[error(display = "_0")]
     Dns(#[error(source)] dns_parser::Error),
-    #[error(display = "_0")]
-    TimeoutError(#[error(source)] async_std::future::TimeoutError),
+    /// A packet we were asked to send could not be built, such as a query for
+    /// a name with overlong labels or with too many questions to fit.
+    #[error(display = "failed to build packet: {}", _0)]
+    PacketBuild(String),
+    /// A received datagram failed to parse.
+    ///
+    /// Only reported when parse errors are surfaced. The datagram's bytes are
+    /// only kept with the `capture-raw` feature.
+    #[error(
+        display = "failed to parse {}-byte datagram from {}: {}",
+        len,
+        addr,
+        source
+    )]
+    ParseError {
+        addr: std::net::SocketAddr,
+        len: usize,
+        raw: Option<Vec<u8>>,
+        #[error(source)]
+        source: dns_parser::Error,
+    },
+    /// No answer arrived before the deadline.
+    ///
+    /// This is kept apart from socket errors so that callers can retry.
+    #[error(display = "timed out")]
+    Timeout,
+}
+
+impl From<async_std::future::TimeoutError> for Error {
+    fn from(_: async_std::future::TimeoutError) -> Self {
+        Error::Timeout
+    }
 }
@@ -0,0 +1,114 @@
+//! A blocking, callback-based interface for use from other languages.
+//!
+//! The rest of this crate is built on async streams, which are hard to expose
+//! across an FFI boundary. The functions here drive discovery internally and
+//! hand each response to a callback instead.
+
+use crate::{Error, Response};
+
+use futures_util::{pin_mut, StreamExt};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::time::Duration;
+
+/// The return code of [`mdns_discover`] when discovery ran to completion.
+pub const MDNS_OK: c_int = 0;
+/// The return code of [`mdns_discover`] when its arguments were invalid.
+pub const MDNS_INVALID_ARGUMENT: c_int = -1;
+/// The return code of [`mdns_discover`] when discovery failed.
+pub const MDNS_ERROR: c_int = -2;
+
+/// A borrowed, C-compatible view of a response.
+///
+/// The strings are nul-terminated and only valid for the duration of the
+/// callback they are passed to.
+#[repr(C)]
+pub struct ResponseView {
+    /// The advertised host name, or null if there is none.
+    pub hostname: *const c_char,
+    /// The first advertised IP address in textual form, or null if there is none.
+    pub ip_addr: *const c_char,
+    /// The advertised port, or `0` if there is none.
+    pub port: u16,
+}
+
+/// Discovers a service for `timeout`, calling `callback` with every response.
+///
+/// This blocks the current thread. Returns `Ok(())` once the timeout elapses.
+pub fn discover_blocking(
+    service_name: &str,
+    timeout: Duration,
+    mut callback: Box<dyn FnMut(&Response) + '_>,
+) -> Result<(), Error> {
+    async_std::task::block_on(async {
+        let stream = crate::discover::all(service_name, timeout)?.listen_continuous(timeout);
+        pin_mut!(stream);
+
+        let process = async {
+            while let Some(response) = stream.next().await {
+                callback(&response?);
+            }
+
+            Ok(())
+        };
+
+        match async_std::future::timeout(timeout, process).await {
+            Ok(result) => result,
+            Err(_) => Ok(()),
+        }
+    })
+}
+
+/// Discovers a service for `timeout_ms` milliseconds, calling `callback` with
+/// every response along with `user_data`.
+///
+/// Returns [`MDNS_OK`] once the timeout elapses, [`MDNS_INVALID_ARGUMENT`] if
+/// the service name is not valid UTF-8, or [`MDNS_ERROR`] if discovery fails.
+///
+/// # Safety
+///
+/// `service_name` must be a valid pointer to a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mdns_discover(
+    service_name: *const c_char,
+    timeout_ms: u64,
+    callback: extern "C" fn(*const ResponseView, *mut c_void),
+    user_data: *mut c_void,
+) -> c_int {
+    if service_name.is_null() {
+        return MDNS_INVALID_ARGUMENT;
+    }
+
+    let service_name = match CStr::from_ptr(service_name).to_str() {
+        Ok(service_name) => service_name,
+        Err(_) => return MDNS_INVALID_ARGUMENT,
+    };
+
+    let result = discover_blocking(
+        service_name,
+        Duration::from_millis(timeout_ms),
+        Box::new(|response| {
+            let hostname = response.hostname().and_then(|host| CString::new(host).ok());
+            let ip_addr = response
+                .ip_addr()
+                .and_then(|addr| CString::new(addr.to_string()).ok());
+
+            let view = ResponseView {
+                hostname: hostname.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+                ip_addr: ip_addr.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+                port: response.port().unwrap_or(0),
+            };
+
+            callback(&view, user_data);
+        }),
+    );
+
+    match result {
+        Ok(()) => MDNS_OK,
+        Err(e) => {
+            log::warn!("discovery failed: {}", e);
+            MDNS_ERROR
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use crate::{Record, RecordKind, Response};
+
+use std::net::{IpAddr, SocketAddr};
+
+/// A fully resolved DNS-SD service instance.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ServiceInstance {
+    /// The instance name, such as `mycast._googlecast._tcp.local`.
+    pub name: String,
+    /// The host the instance runs on, taken from its `SRV` record.
+    pub host: String,
+    pub port: u16,
+    pub addresses: Vec<IpAddr>,
+    /// The `key=value` strings of the instance's `TXT` record.
+    pub txt: Vec<String>,
+}
+
+impl ServiceInstance {
+    /// Builds an instance from the records a response holds for `name`.
+    ///
+    /// Returns `None` if the response has no `SRV` record for the instance.
+    /// The addresses are left empty if the response does not include any for
+    /// the target host.
+    pub fn from_response(name: &str, response: &Response) -> Option<Self> {
+        let (host, port) = response
+            .records()
+            .filter(|record| record.name == name)
+            .find_map(|record| match record.kind {
+                RecordKind::SRV {
+                    port, ref target, ..
+                } => Some((target.clone(), port)),
+                _ => None,
+            })?;
+
+        let mut addresses: Vec<IpAddr> = Vec::new();
+        for addr in response.ip_addrs_of(&host) {
+            if !addresses.contains(&addr) {
+                addresses.push(addr);
+            }
+        }
+
+        let txt = response
+            .records()
+            .filter(|record| record.name == name)
+            .filter_map(Record::txt_raw)
+            .flat_map(|txt| txt.iter())
+            .map(|txt| String::from_utf8_lossy(txt).into_owned())
+            .collect();
+
+        Some(ServiceInstance {
+            name: name.to_owned(),
+            host,
+            port,
+            addresses,
+            txt,
+        })
+    }
+
+    /// Gets every address the instance can be connected to.
+    pub fn socket_addrs(&self) -> Vec<SocketAddr> {
+        self.addresses
+            .iter()
+            .map(|&addr| SocketAddr::new(addr, self.port))
+            .collect()
+    }
+
+    /// Gets the scheme implied by the instance's service type, such as
+    /// `http` for `_http._tcp`, to pass to [`to_url`](Self::to_url).
+    pub fn scheme(&self) -> Option<&str> {
+        let service_end = self
+            .name
+            .find("._tcp.")
+            .or_else(|| self.name.find("._udp."))?;
+        let service = &self.name[..service_end];
+        let service_start = service.rfind("._")?;
+
+        Some(&service[service_start + 2..]).filter(|scheme| !scheme.is_empty())
+    }
+
+    /// Formats a URL for connecting to the instance, such as
+    /// `http://192.168.1.5:8080/`.
+    ///
+    /// An IPv4 address is preferred, and IPv6 addresses are bracketed. The
+    /// path is taken from the `path` key of the `TXT` record, as used by
+    /// HTTP services, defaulting to `/`. Returns `None` if the instance has
+    /// no addresses.
+    ///
+    /// ```rust
+    /// use mdns::ServiceInstance;
+    ///
+    /// let instance = ServiceInstance {
+    ///     name: "printer._http._tcp.local".to_owned(),
+    ///     host: "printer.local".to_owned(),
+    ///     port: 8080,
+    ///     addresses: vec!["fe80::1".parse().unwrap(), "192.168.1.5".parse().unwrap()],
+    ///     txt: vec!["path=/status".to_owned()],
+    /// };
+    ///
+    /// let scheme = instance.scheme().unwrap();
+    /// assert_eq!(instance.to_url(scheme).unwrap(), "http://192.168.1.5:8080/status");
+    /// ```
+    pub fn to_url(&self, scheme: &str) -> Option<String> {
+        let addr = self
+            .addresses
+            .iter()
+            .find(|addr| addr.is_ipv4())
+            .or_else(|| self.addresses.first())?;
+
+        let path = self
+            .txt
+            .iter()
+            .find_map(|entry| entry.strip_prefix("path="))
+            .filter(|path| path.starts_with('/'))
+            .unwrap_or("/");
+
+        Some(format!(
+            "{}://{}{}",
+            scheme,
+            SocketAddr::new(*addr, self.port),
+            path
+        ))
+    }
+
+    fn differs_from(&self, other: &ServiceInstance) -> bool {
+        self.txt != other.txt || self.differs_besides_txt(other)
+    }
+
+    fn differs_besides_txt(&self, other: &ServiceInstance) -> bool {
+        self.host != other.host
+            || self.port != other.port
+            || self.addresses.len() != other.addresses.len()
+            || !self
+                .addresses
+                .iter()
+                .all(|addr| other.addresses.contains(addr))
+    }
+}
+
+/// A change to a service instance seen while browsing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum DeviceEvent {
+    /// An instance was resolved for the first time.
+    Added(ServiceInstance),
+    /// An instance's host, port or addresses changed, and maybe its `TXT`
+    /// record too.
+    Updated {
+        old: ServiceInstance,
+        new: ServiceInstance,
+    },
+    /// Only the `TXT` record of an instance changed.
+    TxtChanged {
+        name: String,
+        old: Vec<String>,
+        new: Vec<String>,
+    },
+    /// An instance said goodbye.
+    Removed(String),
+}
+
+impl DeviceEvent {
+    /// Works out how an instance changed since it was last seen, if at all.
+    pub(crate) fn between(old: Option<&ServiceInstance>, new: &ServiceInstance) -> Option<Self> {
+        let old = match old {
+            Some(old) => old,
+            None => return Some(DeviceEvent::Added(new.clone())),
+        };
+
+        if new.differs_besides_txt(old) {
+            Some(DeviceEvent::Updated {
+                old: old.clone(),
+                new: new.clone(),
+            })
+        } else if new.txt != old.txt {
+            Some(DeviceEvent::TxtChanged {
+                name: new.name.clone(),
+                old: old.txt.clone(),
+                new: new.txt.clone(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// The differences between two scans for service instances.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanDiff {
+    /// Instances only present in the current scan.
+    pub added: Vec<ServiceInstance>,
+    /// Instances only present in the previous scan.
+    pub removed: Vec<ServiceInstance>,
+    /// Instances present in both scans whose details differ, as the previous
+    /// and current versions.
+    pub changed: Vec<(ServiceInstance, ServiceInstance)>,
+}
+
+impl ScanDiff {
+    /// Checks whether the scans were the same.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compares two scans, matching instances by name.
+///
+/// An instance has changed if its host, port, `TXT` record or set of
+/// addresses differs. The order addresses were received in is ignored.
+pub fn diff_scans(prev: &[ServiceInstance], curr: &[ServiceInstance]) -> ScanDiff {
+    let mut diff = ScanDiff::default();
+
+    for instance in curr {
+        match prev.iter().find(|prev| prev.name == instance.name) {
+            Some(previous) if previous.differs_from(instance) => {
+                diff.changed.push((previous.clone(), instance.clone()))
+            }
+            Some(_) => {}
+            None => diff.added.push(instance.clone()),
+        }
+    }
+
+    for instance in prev {
+        if !curr.iter().any(|curr| curr.name == instance.name) {
+            diff.removed.push(instance.clone());
+        }
+    }
+
+    diff
+}
+
+/// Finds addresses advertised by instances on more than one host, which
+/// usually means two devices were assigned the same IP.
+///
+/// Returns each such address with the names of every instance claiming it,
+/// in the order the addresses were first seen. Instances sharing a host, as
+/// when one device advertises several services, don't conflict.
+pub fn detect_address_conflicts(instances: &[ServiceInstance]) -> Vec<(IpAddr, Vec<String>)> {
+    let mut claims: Vec<(IpAddr, Vec<&ServiceInstance>)> = Vec::new();
+
+    for instance in instances {
+        for &addr in instance.addresses.iter() {
+            match claims.iter_mut().find(|(claimed, _)| *claimed == addr) {
+                Some((_, claimants)) => {
+                    if !claimants
+                        .iter()
+                        .any(|claimant| claimant.name == instance.name)
+                    {
+                        claimants.push(instance);
+                    }
+                }
+                None => claims.push((addr, vec![instance])),
+            }
+        }
+    }
+
+    claims
+        .into_iter()
+        .filter(|(_, claimants)| {
+            claimants
+                .iter()
+                .any(|claimant| claimant.host != claimants[0].host)
+        })
+        .map(|(addr, claimants)| {
+            let names = claimants
+                .into_iter()
+                .map(|claimant| claimant.name.clone())
+                .collect();
+            (addr, names)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(name: &str, addresses: &[[u8; 4]]) -> ServiceInstance {
+        ServiceInstance {
+            name: name.to_owned(),
+            host: "printer.local".to_owned(),
+            port: 80,
+            addresses: addresses.iter().map(|&octets| octets.into()).collect(),
+            txt: vec!["path=/".to_owned()],
+        }
+    }
+
+    #[test]
+    fn diff_scans_ignores_the_order_of_addresses() {
+        let prev = [instance("a", &[[10, 0, 0, 1], [10, 0, 0, 2]])];
+        let curr = [instance("a", &[[10, 0, 0, 2], [10, 0, 0, 1]])];
+
+        assert!(diff_scans(&prev, &curr).is_empty());
+    }
+
+    #[test]
+    fn diff_scans_reports_changed_details() {
+        let prev = [
+            instance("address", &[[10, 0, 0, 1]]),
+            instance("port", &[[10, 0, 0, 2]]),
+            instance("txt", &[[10, 0, 0, 3]]),
+            instance("same", &[[10, 0, 0, 4]]),
+        ];
+        let mut curr = prev.clone();
+        curr[0].addresses = vec![[10, 0, 0, 9].into()];
+        curr[1].port = 8080;
+        curr[2].txt = vec!["path=/other".to_owned()];
+
+        let diff = diff_scans(&prev, &curr);
+        let changed: Vec<&str> = diff
+            .changed
+            .iter()
+            .map(|(old, _)| old.name.as_str())
+            .collect();
+        assert_eq!(changed, ["address", "port", "txt"]);
+        assert_eq!(diff.changed[1].1.port, 8080);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+    }
+
+    #[test]
+    fn diff_scans_matches_instances_by_name() {
+        let prev = [instance("gone", &[]), instance("kept", &[])];
+        let curr = [instance("kept", &[]), instance("new", &[])];
+
+        let diff = diff_scans(&prev, &curr);
+        assert_eq!(diff.added, [instance("new", &[])]);
+        assert_eq!(diff.removed, [instance("gone", &[])]);
+        assert!(diff.changed.is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The latencies of the responses to our queries, gathered for percentiles.
+///
+/// Latencies are counted in fixed, log-scaled buckets, so the histogram's size
+/// doesn't grow with the number recorded. Percentiles are accurate to within
+/// about 3%, while the minimum and maximum are exact.
+/// See [`Discovery::with_latency_histogram`](crate::discover::Discovery::with_latency_histogram).
+///
+/// ```rust
+/// use mdns::LatencyHistogram;
+/// use std::time::Duration;
+///
+/// let histogram = LatencyHistogram::new();
+/// for millis in 1..=100 {
+///     histogram.record(Duration::from_millis(millis));
+/// }
+///
+/// let snapshot = histogram.snapshot().unwrap();
+/// assert_eq!(snapshot.count, 100);
+/// assert_eq!(snapshot.min, Duration::from_millis(1));
+/// assert!(snapshot.p50 >= Duration::from_millis(50));
+/// assert!(snapshot.p50 <= Duration::from_millis(51));
+/// assert_eq!(snapshot.max, Duration::from_millis(100));
+/// ```
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    buckets: Mutex<Buckets>,
+}
+
+/// How many bits after a latency's highest set bit, in microseconds, pick its
+/// bucket. Each bucket is at most 1/32 as wide as the latencies in it.
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+/// The highest set bit of the longest latency told apart, about 38 hours in
+/// microseconds. Longer latencies are counted in the last bucket.
+const MAX_EXPONENT: u32 = 37;
+const BUCKET_COUNT: usize = ((MAX_EXPONENT - SUB_BUCKET_BITS + 2) as usize) << SUB_BUCKET_BITS;
+
+#[derive(Debug)]
+struct Buckets {
+    counts: Vec<u64>,
+    count: usize,
+    min: Duration,
+    max: Duration,
+}
+
+/// Gets the bucket a latency of `micros` is counted in.
+fn bucket_of(micros: u64) -> usize {
+    let micros = micros.min((1 << (MAX_EXPONENT + 1)) - 1);
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+
+    let shift = 63 - micros.leading_zeros() - SUB_BUCKET_BITS;
+    ((shift as u64 + 1) * SUB_BUCKETS + (micros >> shift) % SUB_BUCKETS) as usize
+}
+
+/// Gets the longest latency, in microseconds, counted in a bucket.
+fn bucket_max(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+
+    let shift = bucket / SUB_BUCKETS - 1;
+    ((bucket % SUB_BUCKETS + SUB_BUCKETS + 1) << shift) - 1
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: Mutex::new(Buckets {
+                counts: vec![0; BUCKET_COUNT],
+                count: 0,
+                min: Duration::MAX,
+                max: Duration::ZERO,
+            }),
+        }
+    }
+}
+
+/// The percentiles of a [`LatencyHistogram`] at a point in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    pub count: usize,
+    pub min: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyHistogram {
+    /// Creates an empty histogram.
+    pub fn new() -> Self {
+        LatencyHistogram::default()
+    }
+
+    /// Adds a latency.
+    pub fn record(&self, latency: Duration) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+
+        buckets.counts[bucket_of(micros)] += 1;
+        buckets.count += 1;
+        buckets.min = buckets.min.min(latency);
+        buckets.max = buckets.max.max(latency);
+    }
+
+    /// Gets the number of latencies recorded.
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap().count
+    }
+
+    /// Checks whether no latencies have been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the percentiles of the latencies recorded so far, or `None` if
+    /// there are none yet.
+    ///
+    /// Percentiles are taken by nearest rank, as the longest latency in the
+    /// bucket that rank falls in.
+    pub fn snapshot(&self) -> Option<LatencySnapshot> {
+        let buckets = self.buckets.lock().unwrap();
+        if buckets.count == 0 {
+            return None;
+        }
+
+        let percentile = |percent: usize| {
+            let rank = (buckets.count * percent).div_ceil(100).max(1) as u64;
+            let mut seen = 0;
+            let bucket = buckets
+                .counts
+                .iter()
+                .position(|&count| {
+                    seen += count;
+                    seen >= rank
+                })
+                .unwrap_or(BUCKET_COUNT - 1);
+
+            Duration::from_micros(bucket_max(bucket)).clamp(buckets.min, buckets.max)
+        };
+
+        Some(LatencySnapshot {
+            count: buckets.count,
+            min: buckets.min,
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+            max: buckets.max,
+        })
+    }
+}
+
+impl fmt::Display for LatencySnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} responses, min {:?}, p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+            self.count, self.min, self.p50, self.p95, self.p99, self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_hold_their_latencies_within_a_32nd() {
+        let mut micros = 0;
+        while micros < 1 << 40 {
+            let bucket = bucket_of(micros);
+            let max = bucket_max(bucket);
+            let min = if bucket == 0 {
+                0
+            } else {
+                bucket_max(bucket - 1) + 1
+            };
+
+            assert!(bucket < BUCKET_COUNT);
+            if micros < 1 << (MAX_EXPONENT + 1) {
+                assert!(
+                    min <= micros && micros <= max,
+                    "{} in {}..={}",
+                    micros,
+                    min,
+                    max
+                );
+                assert!(
+                    (max - min) * SUB_BUCKETS <= micros.max(1),
+                    "{} in {}..={}",
+                    micros,
+                    min,
+                    max
+                );
+            } else {
+                assert_eq!(bucket, BUCKET_COUNT - 1);
+            }
+
+            micros += micros / 7 + 1;
+        }
+    }
+
+    #[test]
+    fn single_latency_is_every_percentile() {
+        let histogram = LatencyHistogram::new();
+        histogram.record(Duration::from_micros(1500));
+
+        let snapshot = histogram.snapshot().unwrap();
+        assert_eq!(snapshot.p50, Duration::from_micros(1500));
+        assert_eq!(snapshot.p99, Duration::from_micros(1500));
+    }
+
+    #[test]
+    fn percentiles_stay_within_the_recorded_range() {
+        let histogram = LatencyHistogram::new();
+        histogram.record(Duration::from_micros(1500));
+        histogram.record(Duration::from_secs(u64::MAX));
+
+        let snapshot = histogram.snapshot().unwrap();
+        assert_eq!(snapshot.min, Duration::from_micros(1500));
+        assert!(snapshot.p50 >= Duration::from_micros(1500));
+        assert!(snapshot.p50 < Duration::from_micros(1500 * 33 / 32));
+        assert_eq!(snapshot.max, Duration::from_secs(u64::MAX));
+        assert!(snapshot.p99 > Duration::from_secs(60 * 60));
+    }
+}
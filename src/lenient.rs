@@ -0,0 +1,302 @@
+//! A forgiving parser for packets that `dns_parser` rejects.
+//!
+//! Some devices send names containing non-ASCII bytes, or records that are
+//! otherwise malformed. Rather than dropping such a packet entirely, this
+//! recovers every record up to the first one that cannot be read.
+
+use crate::{Record, RecordKind, Response, Section};
+
+use std::convert::TryInto;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// The length of a DNS header.
+const HEADER_LENGTH: usize = 12;
+
+/// The most compression pointers followed while reading a single name.
+const MAX_POINTERS: usize = 32;
+
+/// The bit set on the class of a record that should flush cached copies.
+const CACHE_FLUSH_BIT: u16 = 0x8000;
+
+/// Parses as many records as possible from a packet.
+///
+/// Returns `None` if not even the header and questions could be read.
+pub fn parse(data: &[u8]) -> Option<Response> {
+    let header = dns_parser::Header::parse(data).ok()?;
+    let mut reader = Reader {
+        data,
+        pos: HEADER_LENGTH,
+    };
+
+    for _ in 0..header.questions {
+        reader.name()?;
+        reader.skip(4)?;
+    }
+
+    let mut response = Response {
+        answers: Vec::new(),
+        nameservers: Vec::new(),
+        additional: Vec::new(),
+        rtt: None,
+        local_addr: None,
+        interface_index: None,
+        peer_addr: None,
+    };
+
+    let sections = [
+        (header.answers, &mut response.answers),
+        (header.nameservers, &mut response.nameservers),
+        (header.additional, &mut response.additional),
+    ];
+    let section_kinds = [Section::Answer, Section::Authority, Section::Additional];
+
+    'sections: for (&section, (count, records)) in section_kinds.iter().zip(sections) {
+        for _ in 0..count {
+            match reader.record(section) {
+                Some(Some(record)) => records.push(record),
+                Some(None) => {}
+                None => break 'sections,
+            }
+        }
+    }
+
+    Some(response)
+}
+
+/// Splits the data of a `TXT` record into its strings, cutting a truncated
+/// last string short.
+fn txt_strings(mut rdata: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        let (&len, tail) = rdata.split_first()?;
+        let (string, tail) = tail.split_at(usize::from(len).min(tail.len()));
+        rdata = tail;
+        Some(string)
+    })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Reads a record of `section`, or `Some(None)` for a record that was
+    /// skipped over.
+    fn record(&mut self, section: Section) -> Option<Option<Record>> {
+        let name = self.name()?;
+        let record_type = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let length = self.u16()? as usize;
+
+        let rdata_pos = self.pos;
+        let rdata = self.take(length)?;
+
+        let cache_flush = class & CACHE_FLUSH_BIT != 0;
+        let class = match dns_parser::Class::parse(class & !CACHE_FLUSH_BIT) {
+            Ok(class) => class,
+            Err(_) => return Some(None),
+        };
+
+        let mut rdata_reader = Reader {
+            data: self.data,
+            pos: rdata_pos,
+        };
+        let kind = rdata_reader
+            .kind(record_type, rdata)
+            .unwrap_or_else(|| RecordKind::Unimplemented(rdata.to_vec()));
+
+        Some(Some(Record {
+            name,
+            class,
+            ttl,
+            kind,
+            cache_flush,
+            section,
+        }))
+    }
+
+    fn kind(&mut self, record_type: u16, rdata: &[u8]) -> Option<RecordKind> {
+        use dns_parser::Type;
+
+        let kind = match Type::parse(record_type).ok()? {
+            Type::A => {
+                let octets: [u8; 4] = rdata.try_into().ok()?;
+                RecordKind::A(Ipv4Addr::from(octets))
+            }
+            Type::AAAA => {
+                let octets: [u8; 16] = rdata.try_into().ok()?;
+                RecordKind::AAAA(Ipv6Addr::from(octets))
+            }
+            Type::CNAME => RecordKind::CNAME(self.name()?),
+            Type::NS => RecordKind::NS(self.name()?),
+            Type::PTR => RecordKind::PTR(self.name()?),
+            Type::MX => RecordKind::MX {
+                preference: self.u16()?,
+                exchange: self.name()?,
+            },
+            Type::SRV => RecordKind::SRV {
+                priority: self.u16()?,
+                weight: self.u16()?,
+                port: self.u16()?,
+                target: self.name()?,
+            },
+            Type::TXT => RecordKind::TXT(txt_strings(rdata).map(<[u8]>::to_vec).collect()),
+            _ => return None,
+        };
+
+        Some(kind)
+    }
+
+    /// Reads a possibly compressed name, replacing any invalid UTF-8.
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        let mut pointers = 0;
+
+        loop {
+            let len = *self.data.get(pos)?;
+
+            match len & 0b1100_0000 {
+                0b1100_0000 => {
+                    let low = *self.data.get(pos + 1)?;
+                    end.get_or_insert(pos + 2);
+
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return None;
+                    }
+
+                    pos = usize::from(u16::from_be_bytes([len & 0b0011_1111, low]));
+                }
+                0 if len == 0 => {
+                    self.pos = end.unwrap_or(pos + 1);
+                    break;
+                }
+                0 => {
+                    let label = self.data.get(pos + 1..pos + 1 + usize::from(len))?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + usize::from(len);
+                }
+                _ => return None,
+            }
+        }
+
+        Some(labels.join("."))
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.take(4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header announcing `answers` answers and nothing else.
+    fn header(answers: u16) -> Vec<u8> {
+        let mut data = vec![0, 0, 0x84, 0];
+        for count in [0, answers, 0, 0] {
+            data.extend_from_slice(&u16::to_be_bytes(count));
+        }
+        data
+    }
+
+    /// Appends the fields of an `A` record after its name.
+    fn push_a_fields(data: &mut Vec<u8>, octets: [u8; 4]) {
+        data.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 120, 0, 4]);
+        data.extend_from_slice(&octets);
+    }
+
+    /// Builds a packet whose single `A` record is named through a chain of
+    /// `pointers` compression pointers, counting the one in the record.
+    fn pointer_chain(pointers: usize) -> Vec<u8> {
+        let mut data = header(1);
+        let name_pointer = data.len();
+        data.extend_from_slice(&[0, 0]);
+        push_a_fields(&mut data, [192, 168, 1, 20]);
+
+        let mut target = data.len();
+        data.extend_from_slice(b"\x04host\x05local\x00");
+        for _ in 1..pointers {
+            let pointer = data.len();
+            data.extend_from_slice(&(0xc000 | target as u16).to_be_bytes());
+            target = pointer;
+        }
+        data[name_pointer..name_pointer + 2]
+            .copy_from_slice(&(0xc000 | target as u16).to_be_bytes());
+
+        data
+    }
+
+    #[test]
+    fn follows_pointers_up_to_the_limit() {
+        let response = parse(&pointer_chain(MAX_POINTERS)).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, "host.local");
+    }
+
+    #[test]
+    fn stops_at_a_name_with_too_many_pointers() {
+        let response = parse(&pointer_chain(MAX_POINTERS + 1)).unwrap();
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn stops_at_a_pointer_loop() {
+        let mut data = header(1);
+        let name = data.len() as u16;
+        data.extend_from_slice(&(0xc000 | name).to_be_bytes());
+        push_a_fields(&mut data, [192, 168, 1, 20]);
+
+        let response = parse(&data).unwrap();
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn recovers_records_before_a_malformed_one() {
+        let mut data = header(2);
+        data.extend_from_slice(b"\x04ho\xffs\x05local\x00");
+        push_a_fields(&mut data, [192, 168, 1, 20]);
+        // The second record's data runs past the end of the packet.
+        data.extend_from_slice(b"\x04host\x05local\x00");
+        data.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 120, 0, 4, 192]);
+
+        let response = parse(&data).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, "ho\u{fffd}s.local");
+        assert_eq!(
+            response.answers[0].kind,
+            RecordKind::A([192, 168, 1, 20].into())
+        );
+    }
+
+    #[test]
+    fn cuts_a_truncated_txt_string_short() {
+        let strings: Vec<&[u8]> = txt_strings(b"\x03a=1\x05b=2").collect();
+        assert_eq!(strings, [&b"a=1"[..], &b"b=2"[..]]);
+    }
+
+    #[test]
+    fn rejects_a_truncated_header() {
+        assert_eq!(parse(&[0; 6]), None);
+    }
+}
@@ -57,14 +57,42 @@
 
 #![recursion_limit = "1024"]
 
+pub use self::cache::DiscoveryCache;
+pub use self::clock::{Clock, ManualClock, SystemClock};
+pub use self::connect::connect;
 pub use self::errors::Error;
-pub use self::response::{Record, RecordKind, Response};
+pub use self::instance::{
+    detect_address_conflicts, diff_scans, DeviceEvent, ScanDiff, ServiceInstance,
+};
+pub use self::latency::{LatencyHistogram, LatencySnapshot};
+pub use self::query::{Query, QueryQuestion};
+pub use self::resolve::{is_alive, poll_txt, resolve_service, reverse_name, ResolvedHandle};
+pub use self::response::{Record, RecordKey, RecordKind, Response, Section};
+pub use self::store::{DiscoveryStore, StoreFuture};
 
 pub mod discover;
+pub mod ffi;
+pub mod replay;
 pub mod resolve;
+pub mod respond;
 
+mod cache;
+mod clock;
+mod connect;
+mod daemon;
 mod errors;
+mod instance;
+mod latency;
+mod lenient;
 mod mdns;
+mod packet;
+mod pktinfo;
+mod query;
 mod response;
+mod store;
 
-pub use self::mdns::mDNSListener;
+pub use self::mdns::{
+    interfaces, mDNSListener, mDNSSender, mdns_interface_pool, Datagram, InterfaceInfo, IpVersion,
+    ListenerStats, SocketConfig, SocketInfo,
+};
+pub use if_addrs::Interface;
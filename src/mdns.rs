@@ -1,82 +1,1224 @@
-use crate::{Error, Response};
+use crate::packet::{self, Question};
+use crate::{
+    lenient, pktinfo, replay::Recorder, Clock, DiscoveryCache, Error, Query, Response, SystemClock,
+};
 
-use std::{io, net::Ipv4Addr};
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, Ipv6Addr},
+};
 
-use async_stream::try_stream;
-use futures_core::Stream;
-use std::sync::Arc;
-use async_std::net::UdpSocket;
+use async_stream::stream;
+use futures_core::stream::FusedStream;
+use futures_util::StreamExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_std::{channel, net::UdpSocket};
+use if_addrs::{IfAddr, Interface};
+use socket2::SockRef;
 
 #[cfg(not(target_os = "windows"))]
 use net2::unix::UnixUdpBuilderExt;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::ops::Range;
 
 /// The IP address for the mDNS multicast socket.
-const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
-const MULTICAST_PORT: u16 = 5353;
+pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MULTICAST_PORT: u16 = 5353;
+
+/// The IPv6 address for the mDNS multicast socket.
+pub const MULTICAST_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// The default size limit of our query packets.
+const DEFAULT_MAX_QUERY_SIZE: usize = 1232;
+
+/// The range the random delay before each request is drawn from by default.
+///
+/// See [RFC 6762 §5.2](https://tools.ietf.org/html/rfc6762#section-5.2).
+const DEFAULT_QUERY_JITTER: Range<Duration> = Duration::from_millis(20)..Duration::from_millis(120);
+
+/// How long we wait before receiving again after a transient socket error.
+const RECV_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How many of our most recent queries are remembered, to recognise their
+/// echoes and time the responses to them.
+const SENT_QUERY_HISTORY: usize = 32;
+
+/// Which IP versions mDNS traffic is sent and received over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IpVersion {
+    /// Only IPv4, through the `224.0.0.251` group.
+    #[default]
+    V4,
+    /// Only IPv6, through the `ff02::fb` group.
+    V6,
+    /// Both IPv4 and IPv6.
+    Both,
+}
+
+impl IpVersion {
+    pub(crate) fn includes_v4(self) -> bool {
+        self != IpVersion::V6
+    }
+
+    pub(crate) fn includes_v6(self) -> bool {
+        self != IpVersion::V4
+    }
+}
+
+/// Options used when creating the mDNS socket.
+#[derive(Clone, Debug)]
+pub struct SocketConfig {
+    port: u16,
+    reuse_port: bool,
+    groups: Vec<Ipv4Addr>,
+    include_loopback: bool,
+    dont_fragment: bool,
+    dscp: Option<u8>,
+}
+
+impl SocketConfig {
+    /// Creates the default configuration.
+    pub fn new() -> Self {
+        SocketConfig::default()
+    }
+
+    /// Sets the local port the socket is bound to.
+    ///
+    /// Defaults to 5353. On that port the socket is shared with any other mDNS software on
+    /// the host, and passively receives all multicast traffic on the network, including
+    /// answers to other hosts' queries and unsolicited announcements.
+    ///
+    /// Any other port gives a dedicated, non-shared socket. Responders answer queries sent
+    /// from it by unicast to that port, but no multicast traffic is received, so passive
+    /// co-listening is lost. A port of `0` lets the OS pick one.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Sets whether `SO_REUSEPORT` is set on the shared mDNS socket.
+    ///
+    /// Defaults to `true`. On Linux this lets every process bound to 5353
+    /// receive multicast traffic. On some BSDs it instead load-balances
+    /// datagrams between the sockets, so only one process (possibly avahi or
+    /// mDNSResponder) gets each response; disabling it can help there.
+    /// Windows has no `SO_REUSEPORT`, so this has no effect on it.
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// Sets the multicast groups that are joined and that queries are sent to.
+    ///
+    /// Defaults to just the mDNS group, `224.0.0.251`. Some deployments also
+    /// use site-specific discovery groups; responses received on any of the
+    /// groups are yielded by the same stream.
+    pub fn groups<I>(mut self, groups: I) -> Self
+    where
+        I: IntoIterator<Item = Ipv4Addr>,
+    {
+        self.groups = groups.into_iter().collect();
+        self
+    }
+
+    /// Sets whether the groups are also joined on the loopback interface when
+    /// listening on all interfaces.
+    ///
+    /// Defaults to `false`. Enabling it also loops our queries back to this
+    /// host, so that services published on localhost, such as by avahi during
+    /// development, can be discovered.
+    pub fn include_loopback(mut self, include_loopback: bool) -> Self {
+        self.include_loopback = include_loopback;
+        self
+    }
+
+    /// Sets whether the don't-fragment bit is set on the datagrams we send.
+    ///
+    /// Defaults to `false`. When set, sending a query larger than the path
+    /// MTU fails instead of silently fragmenting it, signalling that the
+    /// [maximum query size](mDNSSender::set_max_query_size) should be
+    /// reduced. Creating the socket fails on platforms without the option.
+    pub fn dont_fragment(mut self, dont_fragment: bool) -> Self {
+        self.dont_fragment = dont_fragment;
+        self
+    }
+
+    /// Sets the DSCP value the datagrams we send are marked with, for networks
+    /// that prioritise traffic by class.
+    ///
+    /// The value is the upper six bits of the IP TOS byte, so it must be below
+    /// 64. Defaults to leaving the OS's marking alone.
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        assert!(dscp < 64, "DSCP values are six bits");
+        self.dscp = Some(dscp);
+        self
+    }
+
+    pub(crate) fn includes_loopback(&self) -> bool {
+        self.include_loopback
+    }
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            port: MULTICAST_PORT,
+            reuse_port: true,
+            groups: vec![MULTICAST_ADDR],
+            include_loopback: false,
+            dont_fragment: false,
+            dscp: None,
+        }
+    }
+}
 
 pub fn mdns_interface(
     service_name: String,
     interface_addr: Ipv4Addr,
-) -> Result<(mDNSListener, mDNSSender), Error> {
-    let socket = create_socket()?;
+    config: &SocketConfig,
+) -> Result<(mDNSListener, mDNSSender, Vec<Interface>), Error> {
+    let (socket, interfaces) = mdns_socket(interface_addr, config)?;
+    let (listener, sender) = from_sockets(service_name, socket.clone(), socket, &config.groups);
+
+    Ok((listener, sender, interfaces))
+}
 
-    socket.set_multicast_loop_v4(false)?;
-    socket.join_multicast_v4(&MULTICAST_ADDR, &interface_addr)?;
+/// Creates `workers` listeners on all interfaces, each on a socket of its own
+/// in the same `SO_REUSEPORT` group, along with a sender sharing the first
+/// socket.
+///
+/// Each listener can be run by a task of its own. Where the kernel load
+/// balances a reuse-port group, such as Linux does for unicast datagrams, the
+/// datagrams are spread across the sockets, sharing the work of parsing them.
+/// Multicast datagrams are delivered to every socket in the group on Linux,
+/// and platforms without `SO_REUSEPORT`, such as Windows, don't balance at
+/// all, so each listener may see every datagram there. The listeners share
+/// their stats and any limit on how many datagrams they parse.
+///
+/// # Panics
+///
+/// Panics if `workers` is zero.
+pub fn mdns_interface_pool(
+    service_name: String,
+    workers: usize,
+) -> Result<(Vec<mDNSListener>, mDNSSender), Error> {
+    assert!(workers > 0, "a pool needs at least one worker");
+
+    let config = SocketConfig::default();
+    let (listener, sender, _) = mdns_interface(service_name, ADDR_ANY, &config)?;
+
+    let mut listeners = Vec::with_capacity(workers);
+    for _ in 1..workers {
+        let (recv, _) = mdns_socket(ADDR_ANY, &config)?;
+        listeners.push(mDNSListener {
+            recv,
+            ..listener.clone()
+        });
+    }
+    listeners.insert(0, listener);
+
+    Ok((listeners, sender))
+}
 
-    let socket = Arc::new(UdpSocket::from(socket));
+/// Like `mdns_interface`, but using a socket the caller has already set up,
+/// which is expected to have joined the mDNS group.
+pub fn mdns_interface_from_socket(
+    service_name: String,
+    socket: Arc<UdpSocket>,
+) -> (mDNSListener, mDNSSender) {
+    from_sockets(service_name, socket.clone(), socket, &[MULTICAST_ADDR])
+}
+
+/// Like `mdns_interface`, but with separate sockets for sending and receiving.
+///
+/// Each socket has its own multicast loopback setting. Linux and macOS apply
+/// loopback to the packets a socket sends, whereas Windows applies it to the
+/// packets a socket receives, so both are configurable.
+pub fn mdns_interface_split(
+    service_name: String,
+    interface_addr: Ipv4Addr,
+    config: &SocketConfig,
+    send_loopback: bool,
+    recv_loopback: bool,
+) -> Result<(mDNSListener, mDNSSender, Vec<Interface>), Error> {
+    let (recv, interfaces) = mdns_socket(interface_addr, config)?;
+    recv.set_multicast_loop_v4(recv_loopback)?;
 
+    let send = create_socket(config)?;
+    send.set_multicast_loop_v4(send_loopback)?;
+    if config.dont_fragment {
+        set_dont_fragment(&send)?;
+    }
+    if let Some(dscp) = config.dscp {
+        SockRef::from(&send).set_tos(u32::from(dscp) << 2)?;
+    }
+    if !interface_addr.is_unspecified() {
+        SockRef::from(&send).set_multicast_if_v4(&interface_addr)?;
+    }
+
+    let (listener, sender) = from_sockets(
+        service_name,
+        recv,
+        Arc::new(UdpSocket::from(send)),
+        &config.groups,
+    );
+
+    Ok((listener, sender, interfaces))
+}
+
+fn from_sockets(
+    service_name: String,
+    recv: Arc<UdpSocket>,
+    send: Arc<UdpSocket>,
+    groups: &[Ipv4Addr],
+) -> (mDNSListener, mDNSSender) {
     let recv_buffer = vec![0; 4096];
+    let query_sent = channel::bounded(1);
+    let sent_queries = Arc::new(Mutex::new(SentQueries::default()));
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
 
-    Ok((
-        mDNSListener { recv: socket.clone(), recv_buffer },
-        mDNSSender { service_name, send: socket },
-    ))
+    (
+        mDNSListener {
+            recv,
+            recv_buffer,
+            groups: groups.to_vec(),
+            ipv4: true,
+            recv_v6: None,
+            v6_interfaces: Vec::new(),
+            sent_queries: sent_queries.clone(),
+            ignore_own_queries: true,
+            lenient: false,
+            surface_parse_errors: false,
+            recorder: None,
+            throttle: Arc::default(),
+            max_bytes: None,
+            stats: ListenerStats::default(),
+            clock: clock.clone(),
+        },
+        mDNSSender {
+            service_name,
+            extra_service_names: Vec::new(),
+            query_type: dns_parser::QueryType::PTR,
+            recursion_desired: false,
+            prefer_unicast: false,
+            jitter: DEFAULT_QUERY_JITTER,
+            known_answers: None,
+            max_query_size: DEFAULT_MAX_QUERY_SIZE,
+            send,
+            groups: groups.to_vec(),
+            ipv4: true,
+            send_v6: None,
+            query_sent,
+            sent_queries,
+            on_query_sent: None,
+            clock,
+        },
+    )
+}
+
+/// Creates a socket that has joined the configured multicast groups.
+///
+/// If `interface_addr` is unspecified, the groups are joined on every
+/// non-loopback IPv4 interface, or every IPv4 interface if the configuration
+/// includes loopback, and those interfaces are returned.
+pub fn mdns_socket(
+    interface_addr: Ipv4Addr,
+    config: &SocketConfig,
+) -> Result<(Arc<UdpSocket>, Vec<Interface>), Error> {
+    #[cfg(feature = "tracing")]
+    let _span =
+        tracing::debug_span!("mdns_interface", %interface_addr, port = config.port).entered();
+
+    let socket = create_socket(config)?;
+
+    socket.set_multicast_loop_v4(config.include_loopback)?;
+    if config.dont_fragment {
+        set_dont_fragment(&socket)?;
+    }
+    if let Some(dscp) = config.dscp {
+        SockRef::from(&socket).set_tos(u32::from(dscp) << 2)?;
+    }
+
+    if let Err(e) = pktinfo::enable(&socket) {
+        log::warn!("failed to enable destination address reporting: {}", e);
+    }
+
+    let interfaces = if interface_addr.is_unspecified() {
+        join_all_interfaces(&socket, &config.groups, config.include_loopback)?
+    } else {
+        for group in &config.groups {
+            socket.join_multicast_v4(group, &interface_addr)?;
+        }
+        Vec::new()
+    };
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(local_addr = ?socket.local_addr().ok(), "created mDNS socket");
+
+    Ok((Arc::new(UdpSocket::from(socket)), interfaces))
+}
+
+/// Joins the multicast groups on every IPv4 interface, skipping loopback
+/// unless `include_loopback` is set.
+///
+/// Falls back to letting the OS pick an interface if none could be joined.
+fn join_all_interfaces(
+    socket: &std::net::UdpSocket,
+    groups: &[Ipv4Addr],
+    include_loopback: bool,
+) -> io::Result<Vec<Interface>> {
+    let mut joined = Vec::new();
+
+    for interface in multicast_interfaces(include_loopback)? {
+        if let IfAddr::V4(ref addr) = interface.addr {
+            let mut joined_any = false;
+
+            for group in groups {
+                match socket.join_multicast_v4(group, &addr.ip) {
+                    Ok(()) => joined_any = true,
+                    Err(e) => log::warn!("failed to join {} on {}: {}", group, interface.name, e),
+                }
+            }
+
+            if joined_any {
+                joined.push(interface);
+            }
+        }
+    }
+
+    if joined.is_empty() {
+        for group in groups {
+            socket.join_multicast_v4(group, &ADDR_ANY)?;
+        }
+    }
+
+    Ok(joined)
+}
+
+/// Creates an IPv6 socket that has joined the mDNS group, `ff02::fb`, on
+/// `interfaces`.
+///
+/// If `interfaces` is empty, the group is joined on every interface with an
+/// IPv6 address, leaving out loopback unless `include_loopback` is set, or on
+/// the OS's default interface if there are none.
+pub(crate) fn mdns_socket_v6(
+    interfaces: &[Interface],
+    include_loopback: bool,
+) -> Result<(Arc<UdpSocket>, Vec<u32>), Error> {
+    let socket = create_socket_v6()?;
+    socket.set_multicast_loop_v6(include_loopback)?;
+
+    let mut indices: Vec<u32> = if interfaces.is_empty() {
+        if_addrs::get_if_addrs()?
+            .into_iter()
+            .filter(|interface| interface.ip().is_ipv6())
+            .filter(|interface| include_loopback || !interface.is_loopback())
+            .filter_map(|interface| interface.index)
+            .collect()
+    } else {
+        interfaces
+            .iter()
+            .filter_map(|interface| interface.index)
+            .collect()
+    };
+    indices.sort_unstable();
+    indices.dedup();
+
+    let mut joined = Vec::new();
+    for index in indices {
+        match socket.join_multicast_v6(&MULTICAST_ADDR_V6, index) {
+            Ok(()) => joined.push(index),
+            Err(e) => log::warn!(
+                "failed to join {} on interface {}: {}",
+                MULTICAST_ADDR_V6,
+                index,
+                e
+            ),
+        }
+    }
+
+    if joined.is_empty() {
+        socket.join_multicast_v6(&MULTICAST_ADDR_V6, 0)?;
+        joined.push(0);
+    }
+
+    Ok((Arc::new(UdpSocket::from(socket)), joined))
+}
+
+/// Lists the IPv4 interfaces the multicast groups can be joined on, leaving
+/// out loopback unless `include_loopback` is set.
+///
+/// The interfaces are ordered by index, then name, as the OS may list them in
+/// any order. This keeps which interface is joined first, and so which one
+/// the OS might favour, the same from run to run.
+pub fn multicast_interfaces(include_loopback: bool) -> io::Result<Vec<Interface>> {
+    let mut candidates = if_addrs::get_if_addrs()?;
+    // The sort is stable, so an interface's addresses keep their order.
+    candidates.sort_by(|a, b| (a.index, &a.name).cmp(&(b.index, &b.name)));
+
+    let mut interfaces: Vec<Interface> = Vec::new();
+
+    for interface in candidates {
+        // An interface with several addresses is listed once per address, but
+        // the groups only need joining once, on its first (primary) IPv4 address.
+        if (interface.is_loopback() && !include_loopback)
+            || !interface.ip().is_ipv4()
+            || interfaces.iter().any(|i| is_same_interface(i, &interface))
+        {
+            continue;
+        }
+
+        interfaces.push(interface);
+    }
+
+    Ok(interfaces)
+}
+
+/// A datagram handed over by [`mDNSListener::listen_owned`].
+///
+/// With the `bytes` feature this is a [`bytes::Bytes`] sharing the receive
+/// buffer; otherwise it is a `Vec<u8>` copied out of it at its exact size.
+#[cfg(feature = "bytes")]
+pub type Datagram = bytes::Bytes;
+/// A datagram handed over by [`mDNSListener::listen_owned`].
+///
+/// With the `bytes` feature this is a [`bytes::Bytes`] sharing the receive
+/// buffer; otherwise it is a `Vec<u8>` copied out of it at its exact size.
+#[cfg(not(feature = "bytes"))]
+pub type Datagram = Vec<u8>;
+
+/// What a listener yields for each datagram it receives.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ListenMode {
+    Responses,
+    Datagrams,
+    Queries,
+}
+
+/// A response or query read from a datagram.
+enum Received {
+    Response(Response, Option<Datagram>),
+    Query(Query),
+}
+
+/// The buffers a listener receives datagrams into.
+struct RecvBuffer {
+    shared: Vec<u8>,
+    /// The buffer datagrams that are handed over are split off from.
+    #[cfg(feature = "bytes")]
+    owned: bytes::BytesMut,
+}
+
+impl RecvBuffer {
+    fn new(shared: Vec<u8>) -> Self {
+        RecvBuffer {
+            shared,
+            #[cfg(feature = "bytes")]
+            owned: bytes::BytesMut::new(),
+        }
+    }
+
+    /// Gets the buffer to receive the next datagram into.
+    fn get(&mut self, owned: bool) -> &mut [u8] {
+        #[cfg(feature = "bytes")]
+        if owned {
+            self.owned.resize(self.shared.len(), 0);
+            return &mut self.owned;
+        }
+
+        let _ = owned;
+        &mut self.shared
+    }
+
+    /// Takes the first `count` bytes of the datagram just received if `owned`.
+    fn take(&mut self, owned: bool, count: usize) -> Option<Datagram> {
+        if !owned {
+            return None;
+        }
+
+        #[cfg(feature = "bytes")]
+        return Some(self.owned.split_to(count).freeze());
+
+        #[cfg(not(feature = "bytes"))]
+        return Some(self.shared[..count].to_vec());
+    }
+}
+
+/// A network interface and its addresses, for picking one to discover on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub ipv4: Vec<Ipv4Addr>,
+    pub ipv6: Vec<Ipv6Addr>,
+    pub is_loopback: bool,
+}
+
+/// Lists the network interfaces of this host, with every address of each.
+///
+/// Interfaces are ordered by index, then name. Any of the IPv4 addresses can
+/// be passed to [`discover::interface`](crate::discover::interface).
+pub fn interfaces() -> Result<Vec<InterfaceInfo>, Error> {
+    let mut addrs = if_addrs::get_if_addrs()?;
+    addrs.sort_by(|a, b| (a.index, &a.name).cmp(&(b.index, &b.name)));
+
+    let mut interfaces: Vec<InterfaceInfo> = Vec::new();
+
+    for addr in addrs {
+        let position = match interfaces.iter().position(|i| i.name == addr.name) {
+            Some(position) => position,
+            None => {
+                interfaces.push(InterfaceInfo {
+                    name: addr.name.clone(),
+                    ipv4: Vec::new(),
+                    ipv6: Vec::new(),
+                    is_loopback: addr.is_loopback(),
+                });
+                interfaces.len() - 1
+            }
+        };
+
+        match addr.addr {
+            IfAddr::V4(ref v4) => interfaces[position].ipv4.push(v4.ip),
+            IfAddr::V6(ref v6) => interfaces[position].ipv6.push(v6.ip),
+        }
+    }
+
+    Ok(interfaces)
+}
+
+/// Checks whether a socket error is likely to clear up by itself, such as
+/// when the network briefly goes down while roaming between access points.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::NetworkDown
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::HostUnreachable
+    )
 }
 
 const ADDR_ANY: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 
+pub fn is_same_interface(a: &Interface, b: &Interface) -> bool {
+    match (a.index, b.index) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.name == b.name,
+    }
+}
+
+/// An address off the local network, used to find the interface carrying the
+/// default route. Nothing is ever sent to it.
+const ROUTE_PROBE_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// Finds the address of the interface carrying the default route.
+///
+/// Connecting a UDP socket sends nothing, but makes the OS pick the source
+/// address it would route through, which is then matched to an interface.
+pub fn default_interface_addr() -> Option<Ipv4Addr> {
+    let probe = std::net::UdpSocket::bind((ADDR_ANY, 0)).ok()?;
+    probe.connect((ROUTE_PROBE_ADDR, 9)).ok()?;
+
+    let local_addr = match probe.local_addr().ok()?.ip() {
+        std::net::IpAddr::V4(addr) => addr,
+        std::net::IpAddr::V6(..) => return None,
+    };
+
+    if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|interface| !interface.is_loopback() && interface.ip() == local_addr)
+        .map(|_| local_addr)
+}
+
 #[cfg(not(target_os = "windows"))]
-fn create_socket() -> io::Result<std::net::UdpSocket> {
+fn create_socket(config: &SocketConfig) -> io::Result<std::net::UdpSocket> {
+    if config.port != MULTICAST_PORT {
+        return create_dedicated_socket(config.port);
+    }
+
     net2::UdpBuilder::new_v4()?
         .reuse_address(true)?
-        .reuse_port(true)?
+        .reuse_port(config.reuse_port)?
         .bind((ADDR_ANY, MULTICAST_PORT))
 }
 
 #[cfg(target_os = "windows")]
-fn create_socket() -> io::Result<std::net::UdpSocket> {
+fn create_socket(config: &SocketConfig) -> io::Result<std::net::UdpSocket> {
+    if config.port != MULTICAST_PORT {
+        return create_dedicated_socket(config.port);
+    }
+
     net2::UdpBuilder::new_v4()?
         .reuse_address(true)?
         .bind((ADDR_ANY, MULTICAST_PORT))
 }
 
+#[cfg(not(target_os = "windows"))]
+fn create_socket_v6() -> io::Result<std::net::UdpSocket> {
+    net2::UdpBuilder::new_v6()?
+        .only_v6(true)?
+        .reuse_address(true)?
+        .reuse_port(true)?
+        .bind((Ipv6Addr::UNSPECIFIED, MULTICAST_PORT))
+}
+
+#[cfg(target_os = "windows")]
+fn create_socket_v6() -> io::Result<std::net::UdpSocket> {
+    net2::UdpBuilder::new_v6()?
+        .only_v6(true)?
+        .reuse_address(true)?
+        .bind((Ipv6Addr::UNSPECIFIED, MULTICAST_PORT))
+}
+
+/// Stops the OS from fragmenting the datagrams a socket sends.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_dont_fragment(socket: &std::net::UdpSocket) -> io::Result<()> {
+    set_ip_option(socket, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO)
+}
+
+/// Stops the OS from fragmenting the datagrams a socket sends.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+fn set_dont_fragment(socket: &std::net::UdpSocket) -> io::Result<()> {
+    set_ip_option(socket, libc::IP_DONTFRAG, 1)
+}
+
+/// Stops the OS from fragmenting the datagrams a socket sends.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+)))]
+fn set_dont_fragment(_: &std::net::UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "don't-fragment is not supported on this platform",
+    ))
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+))]
+fn set_ip_option(
+    socket: &std::net::UdpSocket,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Creates a socket on a port other than the mDNS port, which is not shared with anybody.
+fn create_dedicated_socket(port: u16) -> io::Result<std::net::UdpSocket> {
+    net2::UdpBuilder::new_v4()?.bind((ADDR_ANY, port))
+}
+
+/// The effective options of an mDNS socket, as reported by the OS.
+///
+/// Some systems accept `SO_REUSEADDR`/`SO_REUSEPORT` without actually
+/// granting shared binding; this lets the configuration be confirmed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocketInfo {
+    pub local_addr: SocketAddr,
+    pub reuse_address: bool,
+    /// Whether `SO_REUSEPORT` is set, or `None` on platforms without it.
+    pub reuse_port: Option<bool>,
+    pub multicast_loop_v4: bool,
+    pub multicast_ttl_v4: u32,
+}
+
+impl SocketInfo {
+    fn from_socket(socket: &UdpSocket) -> io::Result<Self> {
+        let sock_ref = SockRef::from(socket);
+
+        Ok(SocketInfo {
+            local_addr: socket.local_addr()?,
+            reuse_address: sock_ref.reuse_address()?,
+            reuse_port: reuse_port(&sock_ref)?,
+            multicast_loop_v4: sock_ref.multicast_loop_v4()?,
+            multicast_ttl_v4: sock_ref.multicast_ttl_v4()?,
+        })
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn reuse_port(sock_ref: &SockRef) -> io::Result<Option<bool>> {
+    sock_ref.reuse_port().map(Some)
+}
+
+#[cfg(target_os = "windows")]
+fn reuse_port(_: &SockRef) -> io::Result<Option<bool>> {
+    Ok(None)
+}
+
 /// An mDNS sender on a specific interface.
 #[derive(Debug, Clone)]
 #[allow(non_camel_case_types)]
 pub struct mDNSSender<> {
     service_name: String,
+    /// Further services queried for alongside `service_name`.
+    extra_service_names: Vec<String>,
+    query_type: dns_parser::QueryType,
+    /// Whether the recursion desired flag is set on our queries.
+    recursion_desired: bool,
+    /// Whether our questions ask for unicast responses.
+    prefer_unicast: bool,
+    /// The range the random delay before each request is drawn from.
+    jitter: Range<Duration>,
+    /// The cache our queries list known answers from, if any.
+    known_answers: Option<Arc<Mutex<DiscoveryCache>>>,
+    /// The largest query packet we send.
+    max_query_size: usize,
     send: Arc<UdpSocket>,
+    /// The multicast groups every query is sent to.
+    groups: Vec<Ipv4Addr>,
+    /// Whether queries are sent over IPv4.
+    ipv4: bool,
+    /// The socket queries are sent over IPv6 through, if they are.
+    send_v6: Option<Arc<UdpSocket>>,
+    /// A channel that is closed once the first query has been sent, waking
+    /// everybody waiting on it. Nothing is ever sent through it.
+    query_sent: (channel::Sender<()>, channel::Receiver<()>),
+    /// The most recent queries we sent, shared with the listener.
+    sent_queries: Arc<Mutex<SentQueries>>,
+    /// Called with every packet we send, if set.
+    on_query_sent: Option<QueryHook>,
+    /// Reads the time our queries are sent at.
+    clock: Arc<dyn Clock>,
+}
+
+/// A callback observing the packets a sender sends.
+#[derive(Clone)]
+struct QueryHook(Arc<QueryHookFn>);
+
+type QueryHookFn = dyn Fn(&[u8], SocketAddr) + Send + Sync;
+
+impl fmt::Debug for QueryHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("QueryHook")
+    }
+}
+
+/// A query we have sent.
+#[derive(Debug)]
+struct SentQuery {
+    questions: Vec<(String, dns_parser::QueryType)>,
+    sent_at: Instant,
+}
+
+impl SentQuery {
+    /// Checks whether a packet asks exactly this query's questions.
+    fn is_asked_by(&self, packet: &dns_parser::Packet) -> bool {
+        packet.questions.len() == self.questions.len()
+            && packet
+                .questions
+                .iter()
+                .zip(&self.questions)
+                .all(|(question, (name, query_type))| {
+                    question.qtype == *query_type && question.qname.to_string() == *name
+                })
+    }
+
+    /// Checks whether a response answers any of this query's questions.
+    fn is_answered_by(&self, response: &Response) -> bool {
+        self.questions
+            .iter()
+            .any(|(name, query_type)| response.answers_query(name, *query_type))
+    }
+}
+
+/// The most recent queries we have sent, along with the addresses they may
+/// come back to us from.
+#[derive(Debug, Default)]
+struct SentQueries {
+    queries: VecDeque<SentQuery>,
+    /// Every local interface address, listed once when the first query is
+    /// sent.
+    local_ips: Option<Vec<std::net::IpAddr>>,
+    /// The ports our queries are sent from.
+    local_ports: Vec<u16>,
+}
+
+impl SentQueries {
+    fn push(&mut self, query: SentQuery) {
+        if self.queries.len() == SENT_QUERY_HISTORY {
+            self.queries.pop_front();
+        }
+        self.queries.push_back(query);
+    }
+
+    /// Checks whether a received packet is one of our queries echoed back to
+    /// us.
+    ///
+    /// mDNS queries all carry an id of 0, so another host asking the same
+    /// question looks just like our own; only the sender address tells them
+    /// apart.
+    fn is_echoed_by(&self, packet: &dns_parser::Packet, peer_addr: SocketAddr) -> bool {
+        packet.header.query
+            && self.local_ports.contains(&peer_addr.port())
+            && self
+                .local_ips
+                .as_ref()
+                .is_some_and(|local_ips| local_ips.contains(&peer_addr.ip()))
+            && self.queries.iter().any(|sent| sent.is_asked_by(packet))
+    }
+
+    /// Gets the time between sending the most recent query a response answers
+    /// and receiving the response at `received_at`.
+    fn rtt(&self, response: &Response, received_at: Instant) -> Option<Duration> {
+        self.queries
+            .iter()
+            .rev()
+            .find(|sent| sent.is_answered_by(response))
+            .and_then(|sent| received_at.checked_duration_since(sent.sent_at))
+    }
 }
 
 impl mDNSSender {
-    /// Send multicasted DNS queries.
+    /// Sets which IP versions queries are sent over, with the IPv6 socket to
+    /// send through if IPv6 is used.
+    pub(crate) fn set_ip_version(
+        &mut self,
+        ip_version: IpVersion,
+        socket_v6: Option<Arc<UdpSocket>>,
+    ) {
+        self.ipv4 = ip_version.includes_v4();
+        self.send_v6 = socket_v6.filter(|_| ip_version.includes_v6());
+    }
+
+    /// Sets the services queried for alongside the main one.
+    pub(crate) fn set_extra_service_names(&mut self, service_names: Vec<String>) {
+        self.extra_service_names = service_names;
+    }
+
+    /// Sets the type of record we ask for in our queries.
+    pub fn set_query_type(&mut self, query_type: dns_parser::QueryType) {
+        self.query_type = query_type;
+    }
+
+    /// Sets the cache whose records are listed as known answers in our queries,
+    /// so that responders don't repeat what we already know.
+    pub fn set_known_answers(&mut self, cache: Arc<Mutex<DiscoveryCache>>) {
+        self.known_answers = Some(cache);
+    }
+
+    /// Sets the clock that reads the time our queries are sent at.
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Sets the largest query packet we send.
+    ///
+    /// Known answers that don't fit are sent in further packets. Defaults to
+    /// 1232 bytes, which avoids fragmentation on any IPv6-capable link.
+    pub fn set_max_query_size(&mut self, max_query_size: usize) {
+        self.max_query_size = max_query_size;
+    }
+
+    /// Sets whether the recursion desired flag is set on our queries.
+    ///
+    /// mDNS has no use for it, so it defaults to `false`, but it can help
+    /// when queries are tunneled to a recursive unicast DNS resolver.
+    pub fn set_recursion_desired(&mut self, recursion_desired: bool) {
+        self.recursion_desired = recursion_desired;
+    }
+
+    /// Sets whether our questions ask for responses to be sent by unicast,
+    /// by setting their QU bit.
+    ///
+    /// Defaults to `false`. See
+    /// [RFC 6762 §5.4](https://tools.ietf.org/html/rfc6762#section-5.4).
+    pub fn set_prefer_unicast(&mut self, prefer_unicast: bool) {
+        self.prefer_unicast = prefer_unicast;
+    }
+
+    /// Sets the range of the random delay before each request is sent.
+    ///
+    /// The delay keeps hosts that start querying at the same moment from
+    /// doing so in lockstep. An empty range sends requests straight away.
+    /// Defaults to 20-120ms, as recommended by
+    /// [RFC 6762 §5.2](https://tools.ietf.org/html/rfc6762#section-5.2).
+    pub fn set_jitter(&mut self, jitter: Range<Duration>) {
+        self.jitter = jitter;
+    }
+
+    /// Sends our multicast queries from the interface with address
+    /// `interface_addr`, rather than the one the OS picks.
+    ///
+    /// This only changes where queries leave from; responses are still
+    /// received on every joined interface.
+    pub fn set_multicast_interface(&mut self, interface_addr: Ipv4Addr) -> Result<(), Error> {
+        SockRef::from(&*self.send).set_multicast_if_v4(&interface_addr)?;
+        Ok(())
+    }
+
+    /// Calls `hook` with the bytes of every packet we send and where it was
+    /// sent to, such as to log the exact queries on the wire.
+    pub fn set_on_query_sent<F>(&mut self, hook: F)
+    where
+        F: Fn(&[u8], SocketAddr) + Send + Sync + 'static,
+    {
+        self.on_query_sent = Some(QueryHook(Arc::new(hook)));
+    }
+
+    /// Sends a packet to every multicast group of the IP versions we use.
+    async fn multicast(&self, packet_data: &[u8]) -> io::Result<()> {
+        if self.ipv4 {
+            for &group in &self.groups {
+                let addr = SocketAddr::new(group.into(), MULTICAST_PORT);
+                self.send_to(packet_data, addr).await?;
+            }
+        }
+
+        if self.send_v6.is_some() {
+            let addr = SocketAddr::new(MULTICAST_ADDR_V6.into(), MULTICAST_PORT);
+            self.send_to(packet_data, addr).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a packet, then tells the [hook](Self::set_on_query_sent) about it.
+    async fn send_to(&self, packet_data: &[u8], addr: SocketAddr) -> io::Result<()> {
+        match self.send_v6 {
+            Some(ref send_v6) if addr.is_ipv6() => send_v6.send_to(packet_data, addr).await?,
+            _ => self.send.send_to(packet_data, addr).await?,
+        };
+
+        if let Some(QueryHook(ref hook)) = self.on_query_sent {
+            hook(packet_data, addr);
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the first query has been sent.
+    ///
+    /// This also completes if every sender is dropped before sending anything.
+    pub fn query_sent(&self) -> impl Future<Output = ()> + Send + 'static {
+        let receiver = self.query_sent.1.clone();
+
+        async move {
+            let _ = receiver.recv().await;
+        }
+    }
+
+    /// Gets the effective options of the underlying socket.
+    pub fn socket_info(&self) -> Result<SocketInfo, Error> {
+        Ok(SocketInfo::from_socket(&self.send)?)
+    }
+
+    pub(crate) fn socket(&self) -> &UdpSocket {
+        &self.send
+    }
+
+    pub(crate) fn socket_v6(&self) -> Option<&UdpSocket> {
+        self.send_v6.as_deref()
+    }
+
+    /// Sends pre-built bytes to `addr`, or to every multicast group if `None`.
+    ///
+    /// This bypasses the queries we build, to send packets with several
+    /// questions, experimental record types or deliberately malformed data.
+    /// The caller is responsible for the packet being valid.
+    pub async fn send_raw(
+        &self,
+        packet_bytes: &[u8],
+        addr: Option<SocketAddr>,
+    ) -> Result<(), Error> {
+        match addr {
+            Some(addr) => {
+                self.send_to(packet_bytes, addr).await?;
+            }
+            None => self.multicast(packet_bytes).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Send multicasted DNS queries, after a random delay within the
+    /// [jitter](Self::set_jitter) range.
     pub async fn send_request(&mut self) -> Result<(), Error> {
-        let mut builder = dns_parser::Builder::new_query(0, false);
-        let prefer_unicast = false;
-        builder.add_question(
-            &self.service_name,
-            prefer_unicast,
-            dns_parser::QueryType::PTR,
-            dns_parser::QueryClass::IN,
-        );
-        let packet_data = builder.build().unwrap();
-
-        let addr = SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT);
-
-        self.send.send_to(&packet_data, addr).await?;
+        if self.jitter.end > self.jitter.start {
+            let span = (self.jitter.end - self.jitter.start).as_nanos() as u64;
+            let delay = self.jitter.start + Duration::from_nanos(fastrand::u64(0..span));
+            async_std::task::sleep(delay).await;
+        }
+
+        let mut service_names = vec![self.service_name.clone()];
+        service_names.extend(self.extra_service_names.iter().cloned());
+
+        for service_name in service_names {
+            self.send_query(&service_name).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a multicasted DNS query for a specific name.
+    pub async fn send_query(&mut self, name: &str) -> Result<(), Error> {
+        let query_type = self.query_type;
+        self.send_query_for(name, query_type).await
+    }
+
+    /// Send a multicasted DNS query for a specific name and type of record.
+    pub async fn send_query_for(
+        &mut self,
+        name: &str,
+        query_type: dns_parser::QueryType,
+    ) -> Result<(), Error> {
+        let id = 0;
+        let known_answers = match self.known_answers {
+            Some(ref cache) => cache.lock().unwrap().known_answers(name, query_type),
+            None => Vec::new(),
+        };
+
+        let packets = if known_answers.is_empty() {
+            vec![self.build_query(id, &[(name, query_type)])?]
+        } else {
+            let question = Question {
+                name,
+                query_type,
+                query_class: dns_parser::QueryClass::IN,
+                prefer_unicast: self.prefer_unicast,
+            };
+
+            packet::build_query(
+                id,
+                self.recursion_desired,
+                &question,
+                &known_answers,
+                self.max_query_size,
+            )
+            .ok_or_else(|| Error::PacketBuild(format!("invalid query name {:?}", name)))?
+        };
+
+        for packet_data in &packets {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(name, query_type = ?query_type, bytes = packet_data.len(), "sending query");
+
+            self.multicast(packet_data).await?;
+        }
+
+        self.record_query(&[(name, query_type)]);
+        self.query_sent.0.close();
+        Ok(())
+    }
+
+    /// Builds a query packet asking `questions`, without any known answers.
+    ///
+    /// Fails rather than panicking if a name has a label too long to encode,
+    /// or the questions don't fit in a single packet.
+    fn build_query(
+        &self,
+        id: u16,
+        questions: &[(&str, dns_parser::QueryType)],
+    ) -> Result<Vec<u8>, Error> {
+        let mut builder = dns_parser::Builder::new_query(id, self.recursion_desired);
+
+        for &(name, query_type) in questions {
+            // The builder panics on labels of 63 bytes or more.
+            if name.split('.').any(|label| label.len() >= 63) {
+                return Err(Error::PacketBuild(format!(
+                    "name {:?} has a label that is too long",
+                    name
+                )));
+            }
+
+            builder.add_question(
+                name,
+                self.prefer_unicast,
+                query_type,
+                dns_parser::QueryClass::IN,
+            );
+        }
+
+        builder
+            .build()
+            .map_err(|_| Error::PacketBuild("questions do not fit in one packet".to_owned()))
+    }
+
+    /// Send a multicasted DNS query asking several questions at once.
+    ///
+    /// Not every responder answers more than the first question of a packet.
+    /// No known answers are listed.
+    pub async fn send_questions(
+        &mut self,
+        questions: &[(&str, dns_parser::QueryType)],
+    ) -> Result<(), Error> {
+        let id = 0;
+        let packet_data = self.build_query(id, questions)?;
+
+        self.multicast(&packet_data).await?;
+
+        self.record_query(questions);
+        self.query_sent.0.close();
         Ok(())
     }
+
+    /// Remembers `questions` as a query we just sent, so the listener can
+    /// tell it apart when it is echoed back to us, and time its responses.
+    fn record_query(&self, questions: &[(&str, dns_parser::QueryType)]) {
+        let query = SentQuery {
+            questions: questions
+                .iter()
+                .map(|&(name, query_type)| (name.to_owned(), query_type))
+                .collect(),
+            sent_at: self.clock.now(),
+        };
+        let local_ports = std::iter::once(&self.send)
+            .chain(self.send_v6.as_ref())
+            .filter_map(|socket| socket.local_addr().ok())
+            .map(|addr| addr.port())
+            .collect();
+
+        let listed = self.sent_queries.lock().unwrap().local_ips.is_some();
+        let local_ips = if listed {
+            None
+        } else {
+            Some(
+                if_addrs::get_if_addrs()
+                    .map(|interfaces| interfaces.iter().map(Interface::ip).collect())
+                    .unwrap_or_default(),
+            )
+        };
+
+        let mut sent_queries = self.sent_queries.lock().unwrap();
+        if local_ips.is_some() {
+            sent_queries.local_ips = local_ips;
+        }
+        sent_queries.local_ports = local_ports;
+        sent_queries.push(query);
+    }
 }
 
 /// An mDNS listener on a specific interface.
@@ -85,21 +1227,444 @@ impl mDNSSender {
 pub struct mDNSListener {
     recv: Arc<UdpSocket>,
     recv_buffer: Vec<u8>,
+    /// The multicast groups the socket has joined.
+    groups: Vec<Ipv4Addr>,
+    /// Whether we receive over IPv4.
+    ipv4: bool,
+    /// The socket we receive over IPv6 through, if we do.
+    recv_v6: Option<Arc<UdpSocket>>,
+    /// The indices of the interfaces the IPv6 socket has joined `ff02::fb` on.
+    v6_interfaces: Vec<u32>,
+    /// The most recent queries the sender sent.
+    sent_queries: Arc<Mutex<SentQueries>>,
+    /// Whether to drop our own queries when they are echoed back to us.
+    ignore_own_queries: bool,
+    /// Whether to recover what we can from datagrams that fail to parse.
+    lenient: bool,
+    /// Whether datagrams that fail to parse are reported as errors.
+    surface_parse_errors: bool,
+    /// Where received datagrams are recorded, if anywhere.
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    /// Limits how many datagrams are parsed, if set. Shared by all clones of
+    /// the listener, so that they are limited together.
+    throttle: Arc<Mutex<Option<Throttle>>>,
+    /// The most bytes received before listening ends, if limited.
+    max_bytes: Option<u64>,
+    stats: ListenerStats,
+    /// Reads the time datagrams are received at.
+    clock: Arc<dyn Clock>,
+}
+
+/// Counters describing the work of a listener, shared by all its clones.
+#[derive(Clone, Debug, Default)]
+pub struct ListenerStats {
+    dropped: Arc<AtomicU64>,
+    bytes_received: Arc<AtomicU64>,
+}
+
+impl ListenerStats {
+    /// Gets the number of datagrams dropped without parsing by throttling.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Gets the total size of the datagrams received, in bytes.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+}
+
+/// A token bucket limiting how many datagrams are parsed per second.
+#[derive(Debug)]
+struct Throttle {
+    per_sec: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Throttle {
+    fn new(per_sec: u32, now: Instant) -> Self {
+        Throttle {
+            per_sec: per_sec.into(),
+            tokens: per_sec.into(),
+            updated_at: now,
+        }
+    }
+
+    /// Checks whether a datagram received at `now` may be parsed.
+    fn allow(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.per_sec);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
 }
 
 impl mDNSListener {
-    pub fn listen(mut self) -> impl Stream<Item = Result<Response, Error>> {
-        try_stream! {
+    /// Starts receiving multicast traffic on an interface.
+    ///
+    /// Interfaces that have already been joined are left as they are.
+    pub fn join_interface(&self, interface_addr: Ipv4Addr) -> Result<(), Error> {
+        for &group in &self.groups {
+            match self.recv.join_multicast_v4(group, interface_addr) {
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops receiving multicast traffic on an interface.
+    pub fn leave_interface(&self, interface_addr: Ipv4Addr) -> Result<(), Error> {
+        for &group in &self.groups {
+            self.recv.leave_multicast_v4(group, interface_addr)?;
+        }
+        Ok(())
+    }
+
+    /// Stops receiving IPv6 multicast traffic on every interface the IPv6
+    /// socket has joined, if we receive over IPv6.
+    pub(crate) fn leave_v6(&self) -> Result<(), Error> {
+        if let Some(ref recv_v6) = self.recv_v6 {
+            let socket = SockRef::from(&**recv_v6);
+            for &index in &self.v6_interfaces {
+                socket.leave_multicast_v6(&MULTICAST_ADDR_V6, index)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets whether to drop our own queries when they are echoed back to us,
+    /// as happens with multicast loopback or on some switches.
+    ///
+    /// Defaults to `true`.
+    pub fn set_ignore_own_queries(&mut self, ignore: bool) {
+        self.ignore_own_queries = ignore;
+    }
+
+    /// Sets whether to recover the readable records of datagrams that fail to
+    /// parse, such as those with non-ASCII names, rather than dropping them.
+    ///
+    /// Defaults to `false`.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Sets whether datagrams that fail to parse, even leniently, are yielded
+    /// as [`Error::ParseError`] rather than only being logged.
+    ///
+    /// The stream carries on after a parse error. Defaults to `false`.
+    pub fn set_surface_parse_errors(&mut self, surface: bool) {
+        self.surface_parse_errors = surface;
+    }
+
+    /// Limits how many datagrams are parsed per second.
+    ///
+    /// Datagrams arriving faster than this are dropped before parsing, and
+    /// counted in the listener's stats. The limit is shared with the
+    /// listener's clones and the other listeners of its pool, and covers the
+    /// datagrams they receive over both IP versions together.
+    pub fn set_max_parse_rate(&mut self, per_sec: u32) {
+        *self.throttle.lock().unwrap() = Some(Throttle::new(per_sec, self.clock.now()));
+    }
+
+    /// Sets the clock that reads the time datagrams are received at.
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Ends listening once `max_bytes` bytes of datagrams have been received,
+    /// as counted in the listener's stats.
+    ///
+    /// The datagram that would go over the limit is dropped, so no more than
+    /// `max_bytes` are ever processed.
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = Some(max_bytes);
+    }
+
+    /// Sets which IP versions we receive over, with the IPv6 socket to receive
+    /// through if IPv6 is used and the interfaces it has joined.
+    pub(crate) fn set_ip_version(
+        &mut self,
+        ip_version: IpVersion,
+        socket_v6: Option<(Arc<UdpSocket>, Vec<u32>)>,
+    ) {
+        self.ipv4 = ip_version.includes_v4();
+        let (recv_v6, v6_interfaces) = socket_v6
+            .filter(|_| ip_version.includes_v6())
+            .map_or((None, Vec::new()), |(socket, interfaces)| {
+                (Some(socket), interfaces)
+            });
+        self.recv_v6 = recv_v6;
+        self.v6_interfaces = v6_interfaces;
+    }
+
+    /// Gets the listener's stats, which stay up to date as it listens.
+    pub fn stats(&self) -> ListenerStats {
+        self.stats.clone()
+    }
+
+    pub(crate) fn socket(&self) -> &UdpSocket {
+        &self.recv
+    }
+
+    pub(crate) fn socket_v6(&self) -> Option<&UdpSocket> {
+        self.recv_v6.as_deref()
+    }
+
+    /// Records every received datagram.
+    pub(crate) fn record_to(&mut self, recorder: Recorder) {
+        self.recorder = Some(Arc::new(Mutex::new(recorder)));
+    }
+
+    /// Listens for responses.
+    ///
+    /// Transient socket errors, such as the network going down for a moment,
+    /// are retried after a short delay. Any other error ends the stream.
+    pub fn listen(self) -> impl FusedStream<Item = Result<Response, Error>> {
+        self.listen_datagrams(ListenMode::Responses)
+            .filter_map(|res| {
+                futures_util::future::ready(match res {
+                    Ok(Received::Response(response, _)) => Some(Ok(response)),
+                    Ok(Received::Query(_)) => None,
+                    Err(e) => Some(Err(e)),
+                })
+            })
+    }
+
+    /// Listens for responses, along with the datagrams they were parsed from.
+    ///
+    /// Each datagram is handed over as a [`Datagram`] of exactly its own size.
+    /// With the `bytes` feature it shares the receive buffer instead of being
+    /// copied out of it.
+    pub fn listen_owned(self) -> impl FusedStream<Item = Result<(Response, Datagram), Error>> {
+        self.listen_datagrams(ListenMode::Datagrams)
+            .filter_map(|res| {
+                futures_util::future::ready(match res {
+                    Ok(Received::Response(response, Some(datagram))) => {
+                        Some(Ok((response, datagram)))
+                    }
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                })
+            })
+    }
+
+    /// Listens for the queries other hosts send, such as to decide how a
+    /// responder should answer them.
+    ///
+    /// Our own queries are left out unless
+    /// [`set_ignore_own_queries`](Self::set_ignore_own_queries) is turned off.
+    pub fn listen_queries(self) -> impl FusedStream<Item = Result<Query, Error>> {
+        self.listen_datagrams(ListenMode::Queries)
+            .filter_map(|res| {
+                futures_util::future::ready(match res {
+                    Ok(Received::Query(query)) => Some(Ok(query)),
+                    Ok(Received::Response(..)) => None,
+                    Err(e) => Some(Err(e)),
+                })
+            })
+    }
+
+    /// Listens on the sockets of the IP versions we use, yielding what `mode`
+    /// asks for.
+    fn listen_datagrams(
+        mut self,
+        mode: ListenMode,
+    ) -> impl FusedStream<Item = Result<Received, Error>> {
+        let mut sockets = Vec::new();
+        if self.ipv4 {
+            sockets.push(self.recv.clone());
+        }
+        sockets.extend(self.recv_v6.take());
+
+        futures_util::stream::select_all(
+            sockets
+                .into_iter()
+                .map(|recv| Box::pin(self.clone().listen_socket(recv, mode))),
+        )
+    }
+
+    /// Listens on a single socket.
+    fn listen_socket(
+        mut self,
+        recv: Arc<UdpSocket>,
+        mode: ListenMode,
+    ) -> impl FusedStream<Item = Result<Received, Error>> {
+        stream! {
+            let mut buffers = RecvBuffer::new(std::mem::take(&mut self.recv_buffer));
+            let owned = mode == ListenMode::Datagrams;
+
             loop {
-                let (count, _) = self.recv.recv_from(&mut self.recv_buffer).await?;
+                let buffer = buffers.get(owned);
+
+                let (count, peer_addr, local_addr, interface_index) =
+                    match pktinfo::recv_from_interface(&recv, buffer).await {
+                        Ok(received) => received,
+                        Err(ref e) if is_transient(e) => {
+                            log::debug!("retrying after transient socket error: {}", e);
+                            async_std::task::sleep(RECV_RETRY_DELAY).await;
+                            continue;
+                        }
+                        Err(e) => {
+                            yield Err(e.into());
+                            break;
+                        }
+                    };
+                let received_at = self.clock.now();
+
+                let bytes_received =
+                    self.stats.bytes_received.fetch_add(count as u64, Ordering::Relaxed)
+                        + count as u64;
+                if self.max_bytes.is_some_and(|max_bytes| bytes_received > max_bytes) {
+                    log::debug!("stopped listening after receiving {} bytes", bytes_received);
+                    break;
+                }
+
+                if let Some(ref recorder) = self.recorder {
+                    if let Err(e) = recorder.lock().unwrap().record(&buffer[..count]) {
+                        log::warn!("failed to record datagram: {}", e);
+                    }
+                }
+
+                #[cfg(feature = "tracing")]
+                tracing::trace!(peer_addr = %peer_addr, bytes = count, "received datagram");
+
+                let allowed = self
+                    .throttle
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .is_none_or(|throttle| throttle.allow(received_at));
+                if !allowed {
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
 
                 if count > 0 {
-                    match dns_parser::Packet::parse(&self.recv_buffer[..count]) {
-                        Ok(raw_packet) => yield Response::from_packet(&raw_packet),
-                        Err(e) => log::warn!("{}, {:?}", e, &self.recv_buffer[..count]),
+                    match dns_parser::Packet::parse(&buffer[..count]) {
+                        Ok(raw_packet) => {
+                            #[cfg(feature = "tracing")]
+                            tracing::debug!(
+                                peer_addr = %peer_addr,
+                                bytes = count,
+                                answers = raw_packet.answers.len(),
+                                "parsed response"
+                            );
+
+                            let is_own_query = self
+                                .sent_queries
+                                .lock()
+                                .unwrap()
+                                .is_echoed_by(&raw_packet, peer_addr);
+
+                            if self.ignore_own_queries && is_own_query {
+                                #[cfg(feature = "tracing")]
+                                tracing::trace!(peer_addr = %peer_addr, "ignoring our own query");
+
+                                continue;
+                            }
+
+                            if mode == ListenMode::Queries {
+                                if let Some(query) = Query::from_packet(&raw_packet) {
+                                    yield Ok(Received::Query(Query {
+                                        peer_addr: Some(peer_addr),
+                                        ..query
+                                    }));
+                                }
+                                continue;
+                            }
+
+                            let mut response = Response::from_packet(&raw_packet);
+                            response.rtt =
+                                self.sent_queries.lock().unwrap().rtt(&response, received_at);
+                            response.local_addr = local_addr;
+                            response.interface_index = interface_index;
+                            response.peer_addr = Some(peer_addr);
+
+                            yield Ok(Received::Response(response, buffers.take(owned, count)))
+                        }
+                        Err(e) => {
+                            if self.lenient && mode != ListenMode::Queries {
+                                if let Some(mut response) = lenient::parse(&buffer[..count]) {
+                                    log::debug!("recovered records from malformed datagram: {}", e);
+
+                                    response.rtt = self
+                                        .sent_queries
+                                        .lock()
+                                        .unwrap()
+                                        .rtt(&response, received_at);
+                                    response.local_addr = local_addr;
+                                    response.interface_index = interface_index;
+                                    response.peer_addr = Some(peer_addr);
+
+                                    let datagram = buffers.take(owned, count);
+                                    yield Ok(Received::Response(response, datagram));
+                                    continue;
+                                }
+                            }
+
+                            #[cfg(feature = "tracing")]
+                            tracing::warn!(peer_addr = %peer_addr, bytes = count, error = %e, "failed to parse datagram");
+
+                            log::warn!("{}, {:?}", e, &buffer[..count]);
+
+                            if self.surface_parse_errors {
+                                let raw = if cfg!(feature = "capture-raw") {
+                                    Some(buffer[..count].to_vec())
+                                } else {
+                                    None
+                                };
+
+                                yield Err(Error::ParseError {
+                                    addr: peer_addr,
+                                    len: count,
+                                    raw,
+                                    source: e,
+                                });
+                            }
+                        }
                     }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender() -> mDNSSender {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let socket = Arc::new(UdpSocket::from(socket));
+        let (_, sender) = from_sockets("_test._tcp.local".to_owned(), socket.clone(), socket, &[]);
+        sender
+    }
+
+    #[test]
+    fn build_query_rejects_overlong_label() {
+        let name = format!("{}.local", "x".repeat(63));
+        let result = sender().build_query(0, &[(&name, dns_parser::QueryType::PTR)]);
+        assert!(matches!(result, Err(Error::PacketBuild(_))));
+    }
+
+    #[test]
+    fn build_query_rejects_questions_too_large_for_a_packet() {
+        let names: Vec<String> = (0..2000)
+            .map(|i| format!("{}-{}.local", "x".repeat(50), i))
+            .collect();
+        let questions: Vec<_> = names
+            .iter()
+            .map(|name| (name.as_str(), dns_parser::QueryType::PTR))
+            .collect();
+        let result = sender().build_query(0, &questions);
+        assert!(matches!(result, Err(Error::PacketBuild(_))));
+    }
+}
@@ -0,0 +1,473 @@
+//! Serialization of the DNS packets `dns_parser` cannot build.
+//!
+//! `dns_parser` can only build queries holding nothing but questions, so
+//! responses and queries carrying known answers are written by hand.
+
+use crate::{Record, RecordKind};
+
+use std::cmp::Ordering;
+
+/// The flags of an authoritative response.
+const RESPONSE_FLAGS: u16 = 0x8400;
+
+/// The flag asking a unicast DNS server to resolve a query recursively.
+const RECURSION_DESIRED_FLAG: u16 = 0x0100;
+
+/// The flag marking a query whose known answers continue in further packets.
+const TRUNCATED_FLAG: u16 = 0x0200;
+
+/// The length of a DNS header.
+const HEADER_LENGTH: usize = 12;
+
+/// The type code of an `NSEC` record, which `dns_parser` doesn't know.
+const NSEC_TYPE: u16 = 47;
+
+/// The bit set on the class of a record that should flush cached copies.
+const CACHE_FLUSH_BIT: u16 = 0x8000;
+
+/// The bit set on the class of a question asking for a unicast response.
+const PREFER_UNICAST_BIT: u16 = 0x8000;
+
+/// The longest label allowed in a DNS name.
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// A question to write into a packet.
+///
+/// Responses copy the questions they answer for legacy unicast replies.
+pub struct Question<'a> {
+    pub name: &'a str,
+    pub query_type: dns_parser::QueryType,
+    pub query_class: dns_parser::QueryClass,
+    /// Whether the question asks for a unicast response (the QU bit).
+    pub prefer_unicast: bool,
+}
+
+/// An `NSEC` record asserting which types of record exist for a name, and
+/// so that no others do.
+///
+/// See [RFC 6762 §6.1](https://tools.ietf.org/html/rfc6762#section-6.1).
+pub struct Nsec {
+    pub name: String,
+    pub ttl: u32,
+    pub cache_flush: bool,
+    pub types: Vec<dns_parser::QueryType>,
+}
+
+impl Nsec {
+    /// Creates the negative answer for a name owning the given records.
+    pub fn for_records(name: &str, records: &[Record]) -> Self {
+        let mut types: Vec<dns_parser::QueryType> = Vec::new();
+
+        for query_type in records.iter().filter_map(|record| record.kind.query_type()) {
+            if !types.contains(&query_type) {
+                types.push(query_type);
+            }
+        }
+
+        Nsec {
+            name: name.to_owned(),
+            ttl: records.iter().map(|record| record.ttl).max().unwrap_or(0),
+            cache_flush: true,
+            types,
+        }
+    }
+}
+
+/// Builds a response packet carrying the given answers.
+///
+/// Records that cannot be serialized, such as those of an unimplemented kind,
+/// are left out.
+pub fn build_response(
+    id: u16,
+    questions: &[Question],
+    answers: &[Record],
+    negative_answers: &[Nsec],
+) -> Vec<u8> {
+    let answers: Vec<Vec<u8>> = answers
+        .iter()
+        .filter_map(encode_record)
+        .chain(negative_answers.iter().filter_map(encode_nsec))
+        .collect();
+    let questions: Vec<Vec<u8>> = questions.iter().filter_map(encode_question).collect();
+
+    write_packet(id, RESPONSE_FLAGS, &questions, &answers)
+}
+
+/// Builds the packets of a query listing the answers we already know.
+///
+/// Known answers that don't fit in a packet of `max_size` bytes are carried
+/// over to further packets without questions, and every packet but the last
+/// has the truncated flag set, as described in
+/// [RFC 6762 §7.2](https://tools.ietf.org/html/rfc6762#section-7.2).
+/// Answers too large for any packet are left out. Returns `None` if the
+/// question's name cannot be written.
+pub fn build_query(
+    id: u16,
+    recursion_desired: bool,
+    question: &Question,
+    known_answers: &[Record],
+    max_size: usize,
+) -> Option<Vec<Vec<u8>>> {
+    let question = encode_question(question)?;
+
+    let mut packets: Vec<Vec<Vec<u8>>> = vec![Vec::new()];
+    let mut size = HEADER_LENGTH + question.len();
+
+    for answer in known_answers.iter().filter_map(encode_record) {
+        if size + answer.len() > max_size {
+            if HEADER_LENGTH + answer.len() > max_size {
+                continue;
+            }
+
+            packets.push(Vec::new());
+            size = HEADER_LENGTH;
+        }
+
+        size += answer.len();
+        packets.last_mut().unwrap().push(answer);
+    }
+
+    let count = packets.len();
+    let mut questions = vec![question];
+
+    let packets = packets
+        .into_iter()
+        .enumerate()
+        .map(|(i, answers)| {
+            let mut flags = 0;
+            if recursion_desired {
+                flags |= RECURSION_DESIRED_FLAG;
+            }
+            if i + 1 < count {
+                flags |= TRUNCATED_FLAG;
+            }
+
+            // Only the first packet asks the question.
+            let packet = write_packet(id, flags, &questions, &answers);
+            questions.clear();
+            packet
+        })
+        .collect();
+
+    Some(packets)
+}
+
+/// Compares two hosts' records of a name to break a tie between
+/// simultaneous probes, as described in
+/// [RFC 6762 §8.2](https://tools.ietf.org/html/rfc6762#section-8.2).
+///
+/// Each set is sorted by class, type and then raw data, and the sets are
+/// compared record by record. The first difference decides, and a set that
+/// runs out first is the lesser. The host whose records are greater wins.
+pub fn compare_records(ours: &[Record], theirs: &[Record]) -> Ordering {
+    fn sorted(records: &[Record]) -> Vec<(u16, u16, Vec<u8>)> {
+        let mut keys: Vec<_> = records
+            .iter()
+            .filter_map(|record| {
+                let query_type = record.kind.query_type()?;
+                Some((
+                    record.class as u16,
+                    query_type as u16,
+                    encode_rdata(record)?,
+                ))
+            })
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    sorted(ours).cmp(&sorted(theirs))
+}
+
+fn write_packet(id: u16, flags: u16, questions: &[Vec<u8>], answers: &[Vec<u8>]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(512);
+
+    write_u16(&mut buf, id);
+    write_u16(&mut buf, flags);
+    write_u16(&mut buf, questions.len() as u16);
+    write_u16(&mut buf, answers.len() as u16);
+    write_u16(&mut buf, 0);
+    write_u16(&mut buf, 0);
+
+    for data in questions.iter().chain(answers.iter()) {
+        buf.extend_from_slice(data);
+    }
+
+    buf
+}
+
+fn encode_question(question: &Question) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    write_name(&mut buf, question.name)?;
+    write_u16(&mut buf, question.query_type as u16);
+
+    let mut class = question.query_class as u16;
+    if question.prefer_unicast {
+        class |= PREFER_UNICAST_BIT;
+    }
+    write_u16(&mut buf, class);
+
+    Some(buf)
+}
+
+fn encode_record(record: &Record) -> Option<Vec<u8>> {
+    let query_type = record.kind.query_type()?;
+    let mut class = record.class as u16;
+
+    if record.cache_flush {
+        class |= CACHE_FLUSH_BIT;
+    }
+
+    let data = encode_rdata(record)?;
+
+    let mut buf = Vec::new();
+    write_name(&mut buf, &record.name)?;
+    write_u16(&mut buf, query_type as u16);
+    write_u16(&mut buf, class);
+    buf.extend_from_slice(&record.ttl.to_be_bytes());
+    write_u16(&mut buf, data.len() as u16);
+    buf.extend_from_slice(&data);
+
+    Some(buf)
+}
+
+/// Encodes the data of a record, with any names in it uncompressed.
+fn encode_rdata(record: &Record) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+
+    match record.kind {
+        RecordKind::A(addr) => data.extend_from_slice(&addr.octets()),
+        RecordKind::AAAA(addr) => data.extend_from_slice(&addr.octets()),
+        RecordKind::CNAME(ref name) | RecordKind::NS(ref name) | RecordKind::PTR(ref name) => {
+            write_name(&mut data, name)?
+        }
+        RecordKind::MX {
+            preference,
+            ref exchange,
+        } => {
+            write_u16(&mut data, preference);
+            write_name(&mut data, exchange)?;
+        }
+        RecordKind::SRV {
+            priority,
+            weight,
+            port,
+            ref target,
+        } => {
+            write_u16(&mut data, priority);
+            write_u16(&mut data, weight);
+            write_u16(&mut data, port);
+            write_name(&mut data, target)?;
+        }
+        RecordKind::TXT(ref strings) => {
+            for string in strings.iter().filter(|s| s.len() <= u8::MAX as usize) {
+                data.push(string.len() as u8);
+                data.extend_from_slice(string);
+            }
+
+            // A TXT record must always contain at least one string.
+            if data.is_empty() {
+                data.push(0);
+            }
+        }
+        RecordKind::Unimplemented(..) => return None,
+    }
+
+    if data.len() > u16::MAX as usize {
+        return None;
+    }
+
+    Some(data)
+}
+
+fn encode_nsec(nsec: &Nsec) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    // The next name is the record's own, as mDNS only uses NSEC for negative answers.
+    write_name(&mut data, &nsec.name)?;
+
+    // Only the first window of the type bitmap is used, as every type we know of fits in it.
+    let mut bitmap = [0u8; 32];
+    for &query_type in nsec.types.iter().filter(|&&t| (t as u16) < 256) {
+        let code = query_type as usize;
+        bitmap[code / 8] |= 0x80 >> (code % 8);
+    }
+
+    let len = bitmap
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |i| i + 1);
+    if len > 0 {
+        data.push(0);
+        data.push(len as u8);
+        data.extend_from_slice(&bitmap[..len]);
+    }
+
+    let mut class = dns_parser::Class::IN as u16;
+    if nsec.cache_flush {
+        class |= CACHE_FLUSH_BIT;
+    }
+
+    let mut buf = Vec::new();
+    write_name(&mut buf, &nsec.name)?;
+    write_u16(&mut buf, NSEC_TYPE);
+    write_u16(&mut buf, class);
+    buf.extend_from_slice(&nsec.ttl.to_be_bytes());
+    write_u16(&mut buf, data.len() as u16);
+    buf.extend_from_slice(&data);
+
+    Some(buf)
+}
+
+/// Writes an uncompressed name, failing if any of its labels are too long.
+fn write_name(buf: &mut Vec<u8>, name: &str) -> Option<()> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+            return None;
+        }
+
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+
+    buf.push(0);
+    Some(())
+}
+
+fn write_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Section;
+
+    fn record(name: &str, kind: RecordKind) -> Record {
+        Record {
+            name: name.to_owned(),
+            class: dns_parser::Class::IN,
+            ttl: 120,
+            kind,
+            cache_flush: false,
+            section: Section::Answer,
+        }
+    }
+
+    fn a(octets: [u8; 4]) -> Record {
+        record("host.local", RecordKind::A(octets.into()))
+    }
+
+    #[test]
+    fn build_query_splits_known_answers_across_truncated_packets() {
+        let question = Question {
+            name: "_http._tcp.local",
+            query_type: dns_parser::QueryType::PTR,
+            query_class: dns_parser::QueryClass::IN,
+            prefer_unicast: false,
+        };
+        let known_answers: Vec<Record> = (0..10)
+            .map(|i| {
+                let instance = format!("instance-{}._http._tcp.local", i);
+                record("_http._tcp.local", RecordKind::PTR(instance))
+            })
+            .collect();
+
+        let packets = build_query(0, false, &question, &known_answers, 200).unwrap();
+        assert!(packets.len() > 1);
+
+        let mut answers = 0;
+        for (i, data) in packets.iter().enumerate() {
+            assert!(data.len() <= 200);
+
+            let packet = dns_parser::Packet::parse(data).unwrap();
+            assert_eq!(packet.questions.len(), if i == 0 { 1 } else { 0 });
+            assert_eq!(packet.header.truncated, i + 1 < packets.len());
+            answers += packet.answers.len();
+        }
+        assert_eq!(answers, known_answers.len());
+    }
+
+    #[test]
+    fn build_query_leaves_out_answers_too_large_for_any_packet() {
+        let question = Question {
+            name: "host.local",
+            query_type: dns_parser::QueryType::TXT,
+            query_class: dns_parser::QueryClass::IN,
+            prefer_unicast: false,
+        };
+        let known_answers = [
+            record("host.local", RecordKind::TXT(vec![vec![b'x'; 255]; 2])),
+            a([192, 168, 1, 20]),
+        ];
+
+        let packets = build_query(0, false, &question, &known_answers, 200).unwrap();
+        assert_eq!(packets.len(), 1);
+
+        let packet = dns_parser::Packet::parse(&packets[0]).unwrap();
+        assert!(!packet.header.truncated);
+        assert_eq!(packet.answers.len(), 1);
+    }
+
+    #[test]
+    fn compare_records_compares_sorted_data() {
+        let low = [a([10, 0, 0, 1])];
+        let high = [a([10, 0, 0, 2])];
+
+        assert_eq!(compare_records(&high, &low), Ordering::Greater);
+        assert_eq!(compare_records(&low, &high), Ordering::Less);
+
+        let ascending = [low[0].clone(), high[0].clone()];
+        let descending = [high[0].clone(), low[0].clone()];
+        assert_eq!(compare_records(&ascending, &descending), Ordering::Equal);
+    }
+
+    #[test]
+    fn compare_records_orders_by_type_before_data() {
+        let aaaa = record("host.local", RecordKind::AAAA([0; 16].into()));
+        let a = a([255, 255, 255, 255]);
+
+        assert_eq!(compare_records(&[aaaa], &[a]), Ordering::Greater);
+    }
+
+    #[test]
+    fn compare_records_ranks_the_set_that_runs_out_first_lower() {
+        let shorter = [a([10, 0, 0, 1])];
+        let longer = [a([10, 0, 0, 1]), a([10, 0, 0, 2])];
+
+        assert_eq!(compare_records(&shorter, &longer), Ordering::Less);
+    }
+
+    #[test]
+    fn encode_nsec_sets_the_types_in_window_zero() {
+        let nsec = Nsec {
+            name: "host.local".to_owned(),
+            ttl: 120,
+            cache_flush: true,
+            types: vec![dns_parser::QueryType::A, dns_parser::QueryType::AAAA],
+        };
+
+        let data = encode_nsec(&nsec).unwrap();
+        // A is type 1, the second bit of the first byte, and AAAA is type 28,
+        // the fifth bit of the fourth.
+        assert!(data.ends_with(&[0, 4, 0x40, 0, 0, 0x08]));
+    }
+
+    #[test]
+    fn encode_nsec_leaves_out_an_empty_bitmap() {
+        let nsec = Nsec {
+            name: "host.local".to_owned(),
+            ttl: 120,
+            cache_flush: true,
+            types: Vec::new(),
+        };
+
+        let mut name = Vec::new();
+        write_name(&mut name, "host.local").unwrap();
+
+        // The record's name and fixed fields, followed by just the next name.
+        let data = encode_nsec(&nsec).unwrap();
+        assert!(data.ends_with(&name));
+        assert_eq!(data.len(), name.len() + 10 + name.len());
+    }
+}
@@ -0,0 +1,159 @@
+//! Receiving datagrams along with the address they were sent to.
+//!
+//! The kernel only reports the destination address of a datagram as ancillary
+//! data, which the standard library's sockets discard. On platforms we don't
+//! know how to ask, the destination is simply not reported. The index of the
+//! interface a datagram arrived on is only reported on Linux and Android.
+
+use async_std::net::UdpSocket;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+))]
+mod imp {
+    use super::Received;
+    use async_std::net::UdpSocket;
+    use std::io;
+    use std::mem;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::os::unix::io::AsRawFd;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const DESTINATION_OPTION: libc::c_int = libc::IP_PKTINFO;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const DESTINATION_OPTION: libc::c_int = libc::IP_RECVDSTADDR;
+
+    pub fn enable(socket: &std::net::UdpSocket) -> io::Result<()> {
+        let enabled: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IP,
+                DESTINATION_OPTION,
+                &enabled as *const libc::c_int as *const libc::c_void,
+                mem::size_of_val(&enabled) as libc::socklen_t,
+            )
+        };
+
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Received> {
+        loop {
+            // Wait for a datagram without consuming it, so that it can be
+            // read along with its ancillary data.
+            socket.peek_from(&mut []).await?;
+
+            match recv_msg(socket, buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn recv_msg(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Received> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut control = [0u64; 16];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of_val(&storage) as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let count = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_DONTWAIT) };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let peer_addr = unsafe { socket2::SockAddr::new(storage, msg.msg_namelen) }
+            .as_socket()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unsupported peer address")
+            })?;
+
+        let mut local_addr = None;
+        let mut interface = None;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+
+            if header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == DESTINATION_OPTION {
+                let (addr, index) = unsafe { destination(cmsg) };
+                local_addr = Some(IpAddr::V4(addr));
+                interface = index;
+            }
+
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+
+        Ok((count as usize, peer_addr, local_addr, interface))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe fn destination(cmsg: *const libc::cmsghdr) -> (Ipv4Addr, Option<u32>) {
+        let info = (libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo).read_unaligned();
+        let addr = Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr));
+        (addr, Some(info.ipi_ifindex as u32))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    unsafe fn destination(cmsg: *const libc::cmsghdr) -> (Ipv4Addr, Option<u32>) {
+        let addr = (libc::CMSG_DATA(cmsg) as *const libc::in_addr).read_unaligned();
+        (Ipv4Addr::from(u32::from_be(addr.s_addr)), None)
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+)))]
+mod imp {
+    use super::Received;
+    use async_std::net::UdpSocket;
+    use std::io;
+
+    pub fn enable(_: &std::net::UdpSocket) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Received> {
+        let (count, peer_addr) = socket.recv_from(buf).await?;
+        Ok((count, peer_addr, None, None))
+    }
+}
+
+/// Asks the OS to report the destination address of received datagrams.
+pub fn enable(socket: &std::net::UdpSocket) -> io::Result<()> {
+    imp::enable(socket)
+}
+
+/// A received datagram's length, sender, destination address and the index of
+/// the interface it arrived on.
+type Received = (usize, SocketAddr, Option<IpAddr>, Option<u32>);
+
+/// Receives a datagram, returning its length, sender, destination address and
+/// the index of the interface it arrived on, where known.
+pub async fn recv_from_interface(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Received> {
+    imp::recv_from(socket, buf).await
+}
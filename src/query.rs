@@ -0,0 +1,62 @@
+use crate::{Record, Section};
+
+use std::net::SocketAddr;
+
+/// A DNS query received from another host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query {
+    pub questions: Vec<QueryQuestion>,
+    /// The answers the querier already holds, which responders should leave
+    /// out. See [RFC 6762 §7.1](https://tools.ietf.org/html/rfc6762#section-7.1).
+    pub known_answers: Vec<Record>,
+    /// The address the query was sent from, if it was received over the
+    /// network.
+    pub peer_addr: Option<SocketAddr>,
+}
+
+/// A question asked by a [`Query`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryQuestion {
+    pub name: String,
+    pub query_type: dns_parser::QueryType,
+    pub query_class: dns_parser::QueryClass,
+    /// Whether the QU bit was set, asking for the answer to be sent by
+    /// unicast rather than multicast. See
+    /// [RFC 6762 §5.4](https://tools.ietf.org/html/rfc6762#section-5.4).
+    pub prefer_unicast: bool,
+}
+
+impl Query {
+    /// Reads the query a packet makes, or `None` if it is a response.
+    pub fn from_packet(packet: &dns_parser::Packet) -> Option<Self> {
+        if !packet.header.query {
+            return None;
+        }
+
+        Some(Query {
+            questions: packet
+                .questions
+                .iter()
+                .map(|question| QueryQuestion {
+                    name: question.qname.to_string(),
+                    query_type: question.qtype,
+                    query_class: question.qclass,
+                    prefer_unicast: question.prefer_unicast,
+                })
+                .collect(),
+            known_answers: packet
+                .answers
+                .iter()
+                .map(|rr| Record::from_resource_record(rr, Section::Answer))
+                .collect(),
+            peer_addr: None,
+        })
+    }
+
+    /// Checks whether any question asks for a unicast answer.
+    pub fn prefers_unicast(&self) -> bool {
+        self.questions
+            .iter()
+            .any(|question| question.prefer_unicast)
+    }
+}
@@ -0,0 +1,150 @@
+//! Utilities for recording discovery sessions and replaying them later.
+//!
+//! A recording holds every datagram received during a session along with when
+//! it arrived, so realistic tests can run without any devices on the network.
+//!
+//! Examples
+//!
+//! ```rust,no_run
+//! use futures_util::{pin_mut, stream::StreamExt};
+//! use mdns::{replay::Pacing, Error};
+//!
+//! #[async_std::main]
+//! async fn main() -> Result<(), Error> {
+//!     let stream = mdns::replay::session("googlecast.mdns", Pacing::Immediate)?;
+//!     pin_mut!(stream);
+//!
+//!     while let Some(Ok(response)) = stream.next().await {
+//!         println!("{:?}", response);
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+
+use crate::{Error, Response};
+
+use async_stream::try_stream;
+use futures_core::stream::FusedStream;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The bytes every recording starts with.
+const MAGIC: &[u8; 8] = b"MDNSREC1";
+
+/// How quickly a recorded session is replayed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pacing {
+    /// Yield responses with the same timing they were recorded with.
+    Original,
+    /// Yield responses as fast as possible.
+    Immediate,
+}
+
+/// Writes received datagrams to a recording.
+///
+/// Each entry is the microseconds since recording started and the datagram
+/// length, both big-endian, followed by the datagram itself.
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    file: File,
+    started_at: Instant,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+
+        Ok(Recorder {
+            file,
+            started_at: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let offset = self.started_at.elapsed().as_micros() as u64;
+
+        let mut entry = Vec::with_capacity(12 + datagram.len());
+        entry.extend_from_slice(&offset.to_be_bytes());
+        entry.extend_from_slice(&(datagram.len() as u32).to_be_bytes());
+        entry.extend_from_slice(datagram);
+
+        self.file.write_all(&entry)
+    }
+}
+
+/// Replays a recorded discovery session.
+///
+/// Datagrams that fail to parse are logged and skipped, just as they were
+/// when recording.
+pub fn session<P>(
+    path: P,
+    pacing: Pacing,
+) -> Result<impl FusedStream<Item = Result<Response, Error>>, Error>
+where
+    P: AsRef<Path>,
+{
+    let datagrams = read_recording(path.as_ref())?;
+
+    Ok(try_stream! {
+        let started_at = Instant::now();
+
+        for (offset, datagram) in datagrams {
+            if pacing == Pacing::Original {
+                if let Some(delay) = (started_at + offset).checked_duration_since(Instant::now()) {
+                    async_std::task::sleep(delay).await;
+                }
+            }
+
+            match dns_parser::Packet::parse(&datagram) {
+                Ok(raw_packet) => yield Response::from_packet(&raw_packet),
+                Err(e) => log::warn!("{}, {:?}", e, &datagram),
+            }
+        }
+    })
+}
+
+fn read_recording(path: &Path) -> io::Result<Vec<(Duration, Vec<u8>)>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    if !data.starts_with(MAGIC) {
+        return Err(invalid_recording());
+    }
+
+    let mut rest = &data[MAGIC.len()..];
+    let mut datagrams = Vec::new();
+
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return Err(invalid_recording());
+        }
+
+        let (header, body) = rest.split_at(12);
+        let mut offset = [0; 8];
+        let mut len = [0; 4];
+        offset.copy_from_slice(&header[..8]);
+        len.copy_from_slice(&header[8..]);
+
+        let len = u32::from_be_bytes(len) as usize;
+        if body.len() < len {
+            return Err(invalid_recording());
+        }
+
+        let (datagram, remaining) = body.split_at(len);
+        datagrams.push((
+            Duration::from_micros(u64::from_be_bytes(offset)),
+            datagram.to_vec(),
+        ));
+        rest = remaining;
+    }
+
+    Ok(datagrams)
+}
+
+fn invalid_recording() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid mDNS recording")
+}
@@ -19,9 +19,18 @@
 //! }
 //! ```
 
-use crate::{Error, Response};
+use crate::{Error, RecordKind, Response, ServiceInstance, SocketConfig};
+use async_lock::Mutex;
+use futures_core::stream::FusedStream;
 use futures_util::{StreamExt, pin_mut, TryFutureExt};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The types of record asked for when looking up a host's addresses.
+const ADDRESS_QUERY_TYPES: [dns_parser::QueryType; 2] =
+    [dns_parser::QueryType::A, dns_parser::QueryType::AAAA];
 
 /// Resolve a single device by hostname
 pub async fn one<S>(
@@ -47,7 +56,7 @@ where
         None
     };
 
-    async_std::future::timeout(timeout, process).map_err(|e| e.into()).await
+    async_std::future::timeout(timeout, process).map_err(|_| Error::Timeout).await
 }
 
 /// Resolve multiple devices by hostname
@@ -82,6 +91,344 @@ where
 
     match async_std::future::timeout(timeout, process).await {
         Ok(()) => Ok(found),
-        Err(e) => Err(e.into())
+        Err(_) => Err(Error::Timeout)
+    }
+}
+
+/// Resolve a service instance, such as `mycast._googlecast._tcp.local`, into
+/// its host, port, addresses and `TXT` record.
+///
+/// Responders usually include the host's addresses alongside the `SRV`
+/// record. If they are missing, the host is queried for them separately with
+/// whatever is left of `timeout`.
+///
+/// Returns `None` if the instance's `SRV` record doesn't arrive in time, and
+/// the instance without any addresses if only its host doesn't answer.
+pub async fn resolve_service(
+    instance_name: &str,
+    timeout: Duration,
+) -> Result<Option<ServiceInstance>, Error> {
+    let deadline = Instant::now() + timeout;
+
+    let instance = first_answer(
+        instance_name,
+        &[dns_parser::QueryType::All],
+        timeout,
+        |response| ServiceInstance::from_response(instance_name, response),
+    )
+    .await?;
+
+    let mut instance = match instance {
+        Some(instance) => instance,
+        None => return Ok(None),
+    };
+
+    if instance.addresses.is_empty() {
+        let host = instance.host.clone();
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let addresses = first_answer(&host, &ADDRESS_QUERY_TYPES, remaining, |response| {
+            let addresses: Vec<_> = response.ip_addrs_of(&host).collect();
+            Some(addresses).filter(|addresses| !addresses.is_empty())
+        })
+        .await?;
+
+        instance.addresses = addresses.unwrap_or_default();
+    }
+
+    Ok(Some(instance))
+}
+
+/// A discovered service instance whose addresses are only queried for when
+/// they are needed, such as once a user picks the instance to connect to.
+///
+/// The addresses found by the first successful resolution are kept, and
+/// shared by every clone of the handle. Addresses the instance was discovered
+/// with are used as they are.
+///
+/// ```rust,no_run
+/// use mdns::{resolve::ResolvedHandle, ServiceInstance};
+/// use std::time::Duration;
+///
+/// # async fn connect_to(instance: ServiceInstance) -> Result<(), mdns::Error> {
+/// let handle = ResolvedHandle::new(instance, Duration::from_secs(3));
+///
+/// for addr in handle.addresses().await? {
+///     println!("{} is at {}", handle.instance().name, addr);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ResolvedHandle {
+    instance: ServiceInstance,
+    /// How long each resolution is given.
+    timeout: Duration,
+    /// The resolved addresses, once found.
+    addresses: Arc<Mutex<Option<Vec<IpAddr>>>>,
+}
+
+impl ResolvedHandle {
+    /// Creates a handle resolving the addresses of `instance`'s host, giving
+    /// each attempt `timeout` to find them.
+    pub fn new(instance: ServiceInstance, timeout: Duration) -> Self {
+        let addresses = Some(instance.addresses.clone()).filter(|addresses| !addresses.is_empty());
+
+        ResolvedHandle {
+            instance,
+            timeout,
+            addresses: Arc::new(Mutex::new(addresses)),
+        }
+    }
+
+    /// Gets the instance this handle resolves.
+    pub fn instance(&self) -> &ServiceInstance {
+        &self.instance
+    }
+
+    /// Gets the instance's addresses, querying its host for its `A` and
+    /// `AAAA` records the first time.
+    ///
+    /// Concurrent calls share a single query. Fails with [`Error::Timeout`]
+    /// if the host doesn't answer in time, in which case the next call
+    /// queries again.
+    pub async fn addresses(&self) -> Result<Vec<IpAddr>, Error> {
+        let mut addresses = self.addresses.lock().await;
+        if let Some(ref addresses) = *addresses {
+            return Ok(addresses.clone());
+        }
+
+        let host = &self.instance.host;
+        let found = first_answer(host, &ADDRESS_QUERY_TYPES, self.timeout, |response| {
+            let mut found: Vec<IpAddr> = Vec::new();
+            for addr in response.ip_addrs_of(host) {
+                if !found.contains(&addr) {
+                    found.push(addr);
+                }
+            }
+            Some(found).filter(|found| !found.is_empty())
+        })
+        .await?
+        .ok_or(Error::Timeout)?;
+
+        *addresses = Some(found.clone());
+        Ok(found)
+    }
+}
+
+/// Checks whether a host, such as `mycast.local`, answers for its addresses
+/// within `timeout`.
+///
+/// This is meant for health checks of known devices: a host that doesn't
+/// answer in time is reported as not alive rather than as an error.
+pub async fn is_alive(hostname: &str, timeout: Duration) -> Result<bool, Error> {
+    let found = first_answer(hostname, &ADDRESS_QUERY_TYPES, timeout, |response| {
+        response.ip_addrs_of(hostname).next()
+    })
+    .await?;
+
+    Ok(found.is_some())
+}
+
+/// Watches the `TXT` record of a known service instance, such as
+/// `mycast._googlecast._tcp.local`, querying for just that record every
+/// `interval`.
+///
+/// The record's `key=value` properties are yielded when first received and
+/// whenever they change. A key without a value maps to an empty string, and
+/// only the first occurrence of a key counts, as described in
+/// [RFC 6763 §6.4](https://tools.ietf.org/html/rfc6763#section-6.4). The
+/// stream ends once the instance hasn't answered for `timeout`, which should
+/// be longer than `interval`.
+///
+/// ```rust,no_run
+/// use futures_util::{pin_mut, StreamExt};
+/// use std::time::Duration;
+///
+/// # async fn watch() -> Result<(), mdns::Error> {
+/// let properties = mdns::poll_txt(
+///     "mycast._googlecast._tcp.local",
+///     Duration::from_secs(5),
+///     Duration::from_secs(20),
+/// )?;
+/// pin_mut!(properties);
+///
+/// while let Some(properties) = properties.next().await {
+///     println!("now playing {:?}", properties.get("rs"));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn poll_txt(
+    instance_name: &str,
+    interval: Duration,
+    timeout: Duration,
+) -> Result<impl FusedStream<Item = HashMap<String, String>>, Error> {
+    let instance_name = instance_name.to_owned();
+    let responses = crate::discover::all(&instance_name, interval)?
+        .query_type(dns_parser::QueryType::TXT)
+        .query_on_listen(true)
+        .listen();
+
+    Ok(async_stream::stream! {
+        pin_mut!(responses);
+        let mut last: Option<HashMap<String, String>> = None;
+
+        loop {
+            let response = match async_std::future::timeout(timeout, responses.next()).await {
+                Ok(Some(Ok(response))) => response,
+                Ok(Some(Err(e))) => {
+                    log::warn!("failed to poll TXT record of {}: {:?}", instance_name, e);
+                    continue;
+                }
+                Ok(None) | Err(_) => break,
+            };
+
+            let txt = response.answers.iter().find_map(|record| match record.kind {
+                RecordKind::TXT(ref txt) if record.name == instance_name => Some(txt),
+                _ => None,
+            });
+            let properties = match txt {
+                Some(txt) => txt_properties(txt),
+                None => continue,
+            };
+
+            if last.as_ref() != Some(&properties) {
+                last = Some(properties.clone());
+                yield properties;
+            }
+        }
+    })
+}
+
+/// Parses the `key=value` strings of a `TXT` record into properties.
+fn txt_properties(txt: &[Vec<u8>]) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+
+    for entry in txt {
+        let entry = String::from_utf8_lossy(entry);
+        let (key, value) = entry.split_once('=').unwrap_or((&entry, ""));
+
+        let duplicate = properties
+            .keys()
+            .any(|known: &String| known.eq_ignore_ascii_case(key));
+
+        if !key.is_empty() && !duplicate {
+            properties.insert(key.to_owned(), value.to_owned());
+        }
     }
+
+    properties
+}
+
+/// Query a name the way legacy resolvers do, from an ephemeral port, and
+/// return the first answer.
+///
+/// Responders reply to queries that don't come from port 5353 directly, with
+/// a single unicast response. See
+/// [RFC 6762 §6.7](https://tools.ietf.org/html/rfc6762#section-6.7). The
+/// question also asks for a unicast response, for responders that only
+/// honour that. The first response is taken as the final answer.
+pub async fn legacy_unicast(
+    name: &str,
+    query_type: dns_parser::QueryType,
+    timeout: Duration,
+) -> Result<Option<Response>, Error> {
+    // by setting the query interval higher than the timeout we ensure we only make one query
+    let stream = crate::discover::all_with_config(name, timeout * 2, SocketConfig::new().port(0))?
+        .query_type(query_type)
+        .prefer_unicast(true)
+        .burst(1);
+    pin_mut!(stream);
+
+    let process = async { stream.next().await.transpose() };
+
+    async_std::future::timeout(timeout, process).map_err(|_| Error::Timeout).await?
+}
+
+/// Resolve the host name of an address with a reverse `PTR` query.
+///
+/// Returns `None` if nothing answers for the address.
+pub async fn reverse(addr: IpAddr, timeout: Duration) -> Result<Option<String>, Error> {
+    let name = reverse_name(addr);
+
+    first_answer(&name, &[dns_parser::QueryType::PTR], timeout, |response| {
+        response
+            .answers
+            .iter()
+            .find_map(|record| match record.kind {
+                RecordKind::PTR(ref host) if record.name == name => Some(host.clone()),
+                _ => None,
+            })
+    })
+    .await
+}
+
+/// Composes the name an address is looked up by in reverse queries.
+///
+/// IPv4 addresses are named by their octets in reverse under `in-addr.arpa`,
+/// and IPv6 addresses by their nibbles in reverse under `ip6.arpa`.
+///
+/// ```rust
+/// use std::net::IpAddr;
+///
+/// let addr: IpAddr = "192.168.1.20".parse().unwrap();
+/// assert_eq!(mdns::reverse_name(addr), "20.1.168.192.in-addr.arpa");
+///
+/// let addr: IpAddr = "fe80::1".parse().unwrap();
+/// assert!(mdns::reverse_name(addr).starts_with("1.0.0.0.0.0.0.0."));
+/// assert!(mdns::reverse_name(addr).ends_with("0.8.e.f.ip6.arpa"));
+/// ```
+pub fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::new();
+
+            for byte in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+
+            name + "ip6.arpa"
+        }
+    }
+}
+
+/// Queries `name` for each of `query_types` until `f` accepts one of the
+/// responses, giving up with `None` once `timeout` has passed.
+async fn first_answer<T, F>(
+    name: &str,
+    query_types: &[dns_parser::QueryType],
+    timeout: Duration,
+    mut f: F,
+) -> Result<Option<T>, Error>
+where
+    F: FnMut(&Response) -> Option<T>,
+{
+    // by setting the query interval higher than the timeout we ensure we only send the queries
+    // below, and matching every response leaves picking out those about `name` to `f`
+    let discovery = crate::discover::all(name, timeout * 2)?.match_all(true);
+    let mut sender = discovery.sender();
+    let stream = discovery.listen();
+    pin_mut!(stream);
+
+    for &query_type in query_types {
+        sender.send_query_for(name, query_type).await?;
+    }
+
+    let process = async {
+        while let Some(response) = stream.next().await {
+            if let Some(found) = f(&response?) {
+                return Ok(Some(found));
+            }
+        }
+
+        Ok(None)
+    };
+
+    async_std::future::timeout(timeout, process)
+        .await
+        .unwrap_or(Ok(None))
 }
@@ -0,0 +1,408 @@
+//! Utilities for answering other hosts' queries on the LAN.
+//!
+//! Examples
+//!
+//! ```rust,no_run
+//! use futures_util::{pin_mut, stream::StreamExt};
+//! use mdns::{DiscoveryCache, Error};
+//! use std::{sync::{Arc, Mutex}, time::Duration};
+//!
+//! const SERVICE_NAME: &'static str = "_googlecast._tcp.local";
+//!
+//! #[async_std::main]
+//! async fn main() -> Result<(), Error> {
+//!     let cache = Arc::new(Mutex::new(DiscoveryCache::new()));
+//!     let proxy = mdns::respond::CacheProxy::new(cache.clone())?;
+//!     async_std::task::spawn(proxy.run());
+//!
+//!     let stream = mdns::discover::all(SERVICE_NAME, Duration::from_secs(15))?
+//!         .cache(cache)
+//!         .listen();
+//!     pin_mut!(stream);
+//!
+//!     while let Some(Ok(response)) = stream.next().await {
+//!         println!("{:?}", response);
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+
+use crate::mdns::{mdns_socket, MULTICAST_ADDR, MULTICAST_PORT};
+use crate::packet::{build_response, compare_records, Nsec, Question};
+use crate::{pktinfo, DiscoveryCache, Error, Record, RecordKind, Section, SocketConfig};
+
+use async_std::net::UdpSocket;
+use if_addrs::{IfAddr, Interface};
+use socket2::SockRef;
+use std::cmp::Ordering;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// The highest TTL allowed in answers to legacy unicast queries.
+///
+/// See [RFC 6762 §6.7](https://tools.ietf.org/html/rfc6762#section-6.7).
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+/// A callback choosing a new name for one that lost a probe tiebreak.
+type ConflictHook = Box<dyn FnMut(&str) -> Option<String> + Send>;
+
+/// A responder that answers other hosts' questions from a discovery cache.
+///
+/// This takes load off the devices we have already discovered, as their
+/// records can be served from our copies. Answers carry the TTL remaining in
+/// the cache and the cache-flush bit of the original record.
+pub struct CacheProxy {
+    cache: Arc<Mutex<DiscoveryCache>>,
+    socket: Arc<UdpSocket>,
+    interfaces: Vec<Interface>,
+    negative_responses: bool,
+}
+
+impl CacheProxy {
+    /// Creates a proxy answering from `cache` on all interfaces.
+    pub fn new(cache: Arc<Mutex<DiscoveryCache>>) -> Result<Self, Error> {
+        let (socket, interfaces) =
+            mdns_socket(Ipv4Addr::new(0, 0, 0, 0), &SocketConfig::default())?;
+
+        Ok(CacheProxy {
+            cache,
+            socket,
+            interfaces,
+            negative_responses: false,
+        })
+    }
+
+    /// Sets whether to assert that a record doesn't exist when asked for a
+    /// type of record we don't hold for a name we do.
+    ///
+    /// Such `NSEC` answers stop well-behaved browsers from querying again,
+    /// but are only correct if the cache holds every record of the names it
+    /// has. Defaults to `false`.
+    pub fn negative_responses(mut self, enabled: bool) -> Self {
+        self.negative_responses = enabled;
+        self
+    }
+
+    /// Answers queries until the socket fails.
+    pub async fn run(self) -> Result<(), Error> {
+        let cache = self.cache;
+
+        serve(
+            &self.socket,
+            &self.interfaces,
+            self.negative_responses,
+            |name, query_type| cache.lock().unwrap().answers(name, query_type),
+            |_| None,
+        )
+        .await
+    }
+}
+
+/// A minimal responder that answers from a fixed set of records.
+///
+/// The records are announced once when it starts, then served without
+/// probing for conflicts of its own or managing their TTLs. As it holds every
+/// record of its names, questions for types it lacks are answered with an
+/// `NSEC` record.
+///
+/// Other hosts probing for one of its names are answered, defending the
+/// name, unless they win the tiebreak of
+/// [RFC 6762 §8.2](https://tools.ietf.org/html/rfc6762#section-8.2). See
+/// [`on_conflict`](Self::on_conflict) for what happens then.
+pub struct StaticResponder {
+    records: Vec<Record>,
+    socket: Arc<UdpSocket>,
+    interfaces: Vec<Interface>,
+    on_conflict: Option<ConflictHook>,
+}
+
+impl StaticResponder {
+    /// Creates a responder serving `records` on all interfaces.
+    pub fn new(records: Vec<Record>) -> Result<Self, Error> {
+        let (socket, interfaces) =
+            mdns_socket(Ipv4Addr::new(0, 0, 0, 0), &SocketConfig::default())?;
+
+        Ok(StaticResponder {
+            records,
+            socket,
+            interfaces,
+            on_conflict: None,
+        })
+    }
+
+    /// Sets the callback choosing a new name when another host probing for
+    /// one of ours wins the tiebreak.
+    ///
+    /// It is called with the name that was lost. Returning a new name renames
+    /// every record of the old one, and those pointing at it, and announces
+    /// them. Returning `None`, or not setting a callback, keeps the name, so
+    /// both hosts go on claiming it.
+    pub fn on_conflict<F>(mut self, on_conflict: F) -> Self
+    where
+        F: FnMut(&str) -> Option<String> + Send + 'static,
+    {
+        self.on_conflict = Some(Box::new(on_conflict));
+        self
+    }
+
+    /// Announces the records, then answers queries until the socket fails.
+    pub async fn run(self) -> Result<(), Error> {
+        let announcement = build_response(0, &[], &self.records, &[]);
+        let addr = SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT);
+        self.socket.send_to(&announcement, addr).await?;
+
+        let records = Mutex::new(self.records);
+        let mut on_conflict = self.on_conflict;
+
+        serve(
+            &self.socket,
+            &self.interfaces,
+            true,
+            |name, query_type| {
+                records
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|record| record.answers(name, query_type))
+                    .cloned()
+                    .collect()
+            },
+            |proposed| {
+                let mut records = records.lock().unwrap();
+                let mut renamed = false;
+
+                let mut names: Vec<&str> = Vec::new();
+                for record in proposed {
+                    if !names.contains(&record.name.as_str()) {
+                        names.push(&record.name);
+                    }
+                }
+
+                for name in names {
+                    let ours: Vec<Record> = records
+                        .iter()
+                        .filter(|record| record.name == name)
+                        .cloned()
+                        .collect();
+                    let theirs: Vec<Record> = proposed
+                        .iter()
+                        .filter(|record| record.name == name)
+                        .cloned()
+                        .collect();
+
+                    if ours.is_empty() || compare_records(&ours, &theirs) != Ordering::Less {
+                        continue;
+                    }
+
+                    match on_conflict
+                        .as_mut()
+                        .and_then(|on_conflict| on_conflict(name))
+                    {
+                        Some(new_name) => {
+                            log::debug!(
+                                "lost probe tiebreak for {}, renaming to {}",
+                                name,
+                                new_name
+                            );
+                            rename(&mut records, name, &new_name);
+                            renamed = true;
+                        }
+                        None => log::warn!("lost probe tiebreak for {}, keeping it", name),
+                    }
+                }
+
+                Some(records.clone()).filter(|_| renamed)
+            },
+        )
+        .await
+    }
+}
+
+/// Renames every record of `old_name`, and every record pointing at it, to
+/// `new_name`.
+fn rename(records: &mut [Record], old_name: &str, new_name: &str) {
+    for record in records.iter_mut() {
+        if record.name == old_name {
+            record.name = new_name.to_owned();
+        }
+
+        match record.kind {
+            RecordKind::PTR(ref mut target) | RecordKind::SRV { ref mut target, .. }
+                if target == old_name =>
+            {
+                *target = new_name.to_owned();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Answers every query received on `socket` with the records found by `lookup`.
+///
+/// With `negative_responses`, questions for names that have records, but
+/// none of the requested type, are answered with an `NSEC` record.
+///
+/// Probes, which propose records in their authority section, are first given
+/// to `probed`. If it returns records, such as after renaming those that lost
+/// the tiebreak, they are announced instead of answering the probe.
+///
+/// Multicast answers leave by the interface the query arrived on, if it is one
+/// of the joined `interfaces`, as answering on another interface would tell
+/// its hosts about addresses they may not be able to reach. Where the OS
+/// doesn't report the interface, it picks one as before.
+async fn serve<F, P>(
+    socket: &UdpSocket,
+    interfaces: &[Interface],
+    negative_responses: bool,
+    mut lookup: F,
+    mut probed: P,
+) -> Result<(), Error>
+where
+    F: FnMut(&str, dns_parser::QueryType) -> Vec<Record>,
+    P: FnMut(&[Record]) -> Option<Vec<Record>>,
+{
+    let mut recv_buffer = vec![0; 4096];
+    let mut multicast_if = Ipv4Addr::new(0, 0, 0, 0);
+
+    loop {
+        let (count, peer_addr, _, interface) =
+            pktinfo::recv_from_interface(socket, &mut recv_buffer).await?;
+
+        let packet = match dns_parser::Packet::parse(&recv_buffer[..count]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::warn!("{}, {:?}", e, &recv_buffer[..count]);
+                continue;
+            }
+        };
+
+        if !packet.header.query {
+            continue;
+        }
+
+        if !packet.nameservers.is_empty() {
+            let proposed: Vec<Record> = packet
+                .nameservers
+                .iter()
+                .map(|rr| Record::from_resource_record(rr, Section::Authority))
+                .collect();
+
+            if let Some(records) = probed(&proposed) {
+                let announcement = build_response(0, &[], &records, &[]);
+                let addr = SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT);
+                socket.send_to(&announcement, addr).await?;
+                continue;
+            }
+        }
+
+        let mut answers: Vec<Record> = Vec::new();
+        let mut negative_answers: Vec<Nsec> = Vec::new();
+        let mut prefer_unicast = false;
+
+        for question in packet.questions.iter() {
+            prefer_unicast |= question.prefer_unicast;
+
+            let name = question.qname.to_string();
+            let found = lookup(&name, question.qtype);
+
+            if found.is_empty()
+                && negative_responses
+                && question.qtype != dns_parser::QueryType::All
+                && !negative_answers.iter().any(|nsec| nsec.name == name)
+            {
+                let existing = lookup(&name, dns_parser::QueryType::All);
+
+                if !existing.is_empty() {
+                    negative_answers.push(Nsec::for_records(&name, &existing));
+                }
+            }
+
+            for record in found {
+                if !answers.contains(&record) {
+                    answers.push(record);
+                }
+            }
+        }
+
+        // Known-answer suppression: leave out anything the querier already
+        // holds with at least half of its TTL remaining.
+        let known_answers: Vec<Record> = packet
+            .answers
+            .iter()
+            .map(|rr| Record::from_resource_record(rr, Section::Answer))
+            .collect();
+        answers.retain(|answer| {
+            !known_answers.iter().any(|known| {
+                known.name == answer.name
+                    && known.kind == answer.kind
+                    && known.ttl >= answer.ttl / 2
+            })
+        });
+
+        if answers.is_empty() && negative_answers.is_empty() {
+            continue;
+        }
+
+        let legacy_unicast = peer_addr.port() != MULTICAST_PORT;
+
+        let packet_data = if legacy_unicast {
+            let question_names: Vec<String> = packet
+                .questions
+                .iter()
+                .map(|q| q.qname.to_string())
+                .collect();
+            let questions: Vec<Question> = packet
+                .questions
+                .iter()
+                .zip(question_names.iter())
+                .map(|(question, name)| Question {
+                    name,
+                    query_type: question.qtype,
+                    query_class: question.qclass,
+                    prefer_unicast: false,
+                })
+                .collect();
+
+            for answer in answers.iter_mut() {
+                answer.ttl = answer.ttl.min(LEGACY_UNICAST_TTL);
+                answer.cache_flush = false;
+            }
+            for nsec in negative_answers.iter_mut() {
+                nsec.ttl = nsec.ttl.min(LEGACY_UNICAST_TTL);
+                nsec.cache_flush = false;
+            }
+
+            build_response(packet.header.id, &questions, &answers, &negative_answers)
+        } else {
+            build_response(0, &[], &answers, &negative_answers)
+        };
+
+        let addr = if legacy_unicast || prefer_unicast {
+            peer_addr
+        } else {
+            let interface_addr = interface_addr(interfaces, interface);
+            if interface_addr != multicast_if {
+                SockRef::from(socket).set_multicast_if_v4(&interface_addr)?;
+                multicast_if = interface_addr;
+            }
+
+            SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT)
+        };
+
+        socket.send_to(&packet_data, addr).await?;
+    }
+}
+
+/// Finds the IPv4 address of the joined interface with index `index`, or the
+/// unspecified address, which lets the OS choose, if it isn't one of them.
+fn interface_addr(interfaces: &[Interface], index: Option<u32>) -> Ipv4Addr {
+    interfaces
+        .iter()
+        .filter(|interface| index.is_some() && interface.index == index)
+        .find_map(|interface| match interface.addr {
+            IfAddr::V4(ref addr) => Some(addr.ip),
+            _ => None,
+        })
+        .unwrap_or(Ipv4Addr::new(0, 0, 0, 0))
+}
@@ -1,25 +1,68 @@
+use std::borrow::Cow;
+use std::hash::{Hash, Hasher};
 use std::net;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::time::Duration;
 
 /// A DNS response.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Response {
     pub answers: Vec<Record>,
     pub nameservers: Vec<Record>,
     pub additional: Vec<Record>,
+    /// The time between sending the most recent of our queries that this
+    /// response answers and receiving it.
+    ///
+    /// `None` if it doesn't answer any of our recent queries, such as an
+    /// unsolicited announcement.
+    pub rtt: Option<Duration>,
+    /// The address the response was sent to, such as the multicast group it
+    /// was received on, if the platform reports it.
+    pub local_addr: Option<IpAddr>,
+    /// The index of the interface the response arrived on, if the platform
+    /// reports it. Link-local IPv6 addresses in the response are scoped to it.
+    pub interface_index: Option<u32>,
+    /// The address the response was sent from, if it was received over the
+    /// network.
+    pub peer_addr: Option<SocketAddr>,
 }
 
 /// Any type of DNS record.
+///
+/// Records are only equal if their TTLs are too. Use [`Record::key`] to
+/// compare records regardless of how long they have left to live.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Record {
     pub name: String,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_class"))]
     pub class: dns_parser::Class,
     pub ttl: u32,
     pub kind: RecordKind,
+    /// Whether the cache-flush bit was set, meaning this record replaces any
+    /// cached records with the same name and type.
+    pub cache_flush: bool,
+    /// The section of the packet the record came from.
+    pub section: Section,
+}
+
+/// A section of a DNS packet holding records.
+///
+/// Records in the answer section answer the question, while those in the
+/// additional section are only offered alongside, so may be trusted less.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Section {
+    #[default]
+    Answer,
+    Authority,
+    Additional,
 }
 
 /// A specific DNS record variant.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum RecordKind {
     A(net::Ipv4Addr),
     AAAA(net::Ipv6Addr),
@@ -35,10 +78,32 @@ pub enum RecordKind {
         port: u16,
         target: String,
     },
-    TXT(Vec<String>),
+    /// The strings of a `TXT` record exactly as received, which may not be
+    /// valid UTF-8.
+    ///
+    /// When serialized, each string is written escaped, like the bytes of an
+    /// [unimplemented](Self::Unimplemented) record.
+    TXT(
+        #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_escaped_strings"))]
+        Vec<Vec<u8>>,
+    ),
     PTR(String),
     /// A record kind that hasn't been implemented by this library yet.
-    Unimplemented(Vec<u8>),
+    ///
+    /// When serialized, the raw bytes are written as an escaped string.
+    Unimplemented(
+        #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_escaped_bytes"))] Vec<u8>,
+    ),
+}
+
+/// The identity of a record, leaving out its TTL and cache-flush bit, for
+/// deduplicating records and keying caches.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RecordKey {
+    pub name: String,
+    /// The numeric value of the record's class.
+    pub class: u16,
+    pub kind: RecordKind,
 }
 
 impl Response {
@@ -47,18 +112,22 @@ impl Response {
             answers: packet
                 .answers
                 .iter()
-                .map(Record::from_resource_record)
+                .map(|rr| Record::from_resource_record(rr, Section::Answer))
                 .collect(),
             nameservers: packet
                 .nameservers
                 .iter()
-                .map(Record::from_resource_record)
+                .map(|rr| Record::from_resource_record(rr, Section::Authority))
                 .collect(),
             additional: packet
                 .additional
                 .iter()
-                .map(Record::from_resource_record)
+                .map(|rr| Record::from_resource_record(rr, Section::Additional))
                 .collect(),
+            rtt: None,
+            local_addr: None,
+            interface_index: None,
+            peer_addr: None,
         }
     }
 
@@ -69,6 +138,42 @@ impl Response {
             .chain(self.additional.iter())
     }
 
+    /// Checks whether the answer section has a record of the requested type
+    /// for the requested name.
+    ///
+    /// Responders multicast their answers, so we also receive responses to
+    /// other hosts' queries. This tells those apart from answers to ours.
+    pub fn answers_query(&self, name: &str, query_type: dns_parser::QueryType) -> bool {
+        self.answers
+            .iter()
+            .any(|record| record.answers(name, query_type))
+    }
+
+    /// Removes every record that does not match a predicate from all sections.
+    pub(crate) fn retain_records<F>(&mut self, mut predicate: F)
+    where
+        F: FnMut(&Record) -> bool,
+    {
+        self.answers.retain(|record| predicate(record));
+        self.nameservers.retain(|record| predicate(record));
+        self.additional.retain(|record| predicate(record));
+    }
+
+    /// Gets the number of records in the answer section.
+    pub fn answer_count(&self) -> usize {
+        self.answers.len()
+    }
+
+    /// Gets the number of records in the authority section.
+    pub fn nameserver_count(&self) -> usize {
+        self.nameservers.len()
+    }
+
+    /// Gets the number of records in the additional section.
+    pub fn additional_count(&self) -> usize {
+        self.additional.len()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.answers.is_empty() && self.nameservers.is_empty() && self.additional.is_empty()
     }
@@ -96,32 +201,222 @@ impl Response {
     }
 
     pub fn socket_address(&self) -> Option<SocketAddr> {
-        Some((self.ip_addr()?, self.port()?).into())
+        self.scoped_socket_addr(self.ip_addr()?, self.port()?)
+    }
+
+    /// Gets every address the advertised service can be connected to.
+    ///
+    /// Each `SRV` record's port is paired with the `A`/`AAAA` records of its
+    /// target. If no address records name a target, the port of the first
+    /// `SRV` record is paired with every address in the response instead.
+    ///
+    /// Link-local IPv6 addresses are scoped to the interface the response
+    /// arrived on, and left out if it isn't known.
+    pub fn socket_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+
+        for record in self.records() {
+            if let RecordKind::SRV {
+                port, ref target, ..
+            } = record.kind
+            {
+                for addr in self
+                    .ip_addrs_of(target)
+                    .filter_map(|addr| self.scoped_socket_addr(addr, port))
+                {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            }
+        }
+
+        if addrs.is_empty() {
+            if let Some(port) = self.port() {
+                for addr in self
+                    .ip_addrs()
+                    .filter_map(|addr| self.scoped_socket_addr(addr, port))
+                {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            }
+        }
+
+        addrs
     }
 
-    pub fn txt_records(&self) -> impl Iterator<Item = &str> {
+    /// Pairs an address from the response with a port, scoping link-local
+    /// IPv6 addresses to the interface the response arrived on.
+    ///
+    /// `None` for a link-local address when that interface isn't known, as
+    /// it can't be connected to without one.
+    fn scoped_socket_addr(&self, addr: IpAddr, port: u16) -> Option<SocketAddr> {
+        match addr {
+            IpAddr::V6(addr) if addr.is_unicast_link_local() => {
+                let scope_id = self.interface_index.or(match self.peer_addr {
+                    Some(SocketAddr::V6(peer_addr)) if peer_addr.scope_id() != 0 => {
+                        Some(peer_addr.scope_id())
+                    }
+                    _ => None,
+                })?;
+                Some(SocketAddrV6::new(addr, port, 0, scope_id).into())
+            }
+            addr => Some(SocketAddr::new(addr, port)),
+        }
+    }
+
+    pub(crate) fn ip_addrs(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.records().filter_map(|record| match record.kind {
+            RecordKind::A(addr) => Some(addr.into()),
+            RecordKind::AAAA(addr) => Some(addr.into()),
+            _ => None,
+        })
+    }
+
+    pub(crate) fn ip_addrs_of<'a>(&'a self, host: &'a str) -> impl Iterator<Item = IpAddr> + 'a {
         self.records()
+            .filter(move |record| record.name == host)
             .filter_map(|record| match record.kind {
-                RecordKind::TXT(ref txt) => Some(txt),
+                RecordKind::A(addr) => Some(addr.into()),
+                RecordKind::AAAA(addr) => Some(addr.into()),
                 _ => None,
             })
+    }
+
+    /// Gets the targets and ports of all `SRV` records, in the order they
+    /// should be tried.
+    ///
+    /// As specified by [RFC 2782](https://tools.ietf.org/html/rfc2782), targets
+    /// are ordered by lowest priority first, and targets sharing a priority are
+    /// shuffled randomly in proportion to their weights.
+    pub fn srv_targets_sorted(&self) -> Vec<(String, u16)> {
+        let mut srvs: Vec<(u16, u16, &str, u16)> = self
+            .records()
+            .filter_map(|record| match record.kind {
+                RecordKind::SRV {
+                    priority,
+                    weight,
+                    port,
+                    ref target,
+                } => Some((priority, weight, target.as_str(), port)),
+                _ => None,
+            })
+            .collect();
+        srvs.sort_by_key(|&(priority, weight, ..)| (priority, weight != 0));
+
+        let mut sorted = Vec::with_capacity(srvs.len());
+
+        while !srvs.is_empty() {
+            let priority = srvs[0].0;
+            let group_len = srvs.iter().take_while(|srv| srv.0 == priority).count();
+            let mut group: Vec<_> = srvs.drain(..group_len).collect();
+
+            while !group.is_empty() {
+                let total: u32 = group.iter().map(|srv| u32::from(srv.1)).sum();
+                let chosen = fastrand::u32(0..=total);
+
+                let mut running_sum = 0;
+                let index = group
+                    .iter()
+                    .position(|srv| {
+                        running_sum += u32::from(srv.1);
+                        running_sum >= chosen
+                    })
+                    .unwrap_or(0);
+
+                let (_, _, target, port) = group.remove(index);
+                sorted.push((target.to_owned(), port));
+            }
+        }
+
+        sorted
+    }
+
+    /// Gets the strings of every `TXT` record, with any bytes that aren't
+    /// valid UTF-8 replaced.
+    pub fn txt_records(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        self.records()
+            .filter_map(Record::txt_raw)
             .flat_map(|txt| txt.iter())
-            .map(|txt| txt.as_str())
+            .map(|txt| String::from_utf8_lossy(txt))
     }
 }
 
 impl Record {
-    fn from_resource_record(rr: &dns_parser::ResourceRecord) -> Self {
+    pub(crate) fn from_resource_record(rr: &dns_parser::ResourceRecord, section: Section) -> Self {
         Record {
             name: rr.name.to_string(),
             class: rr.cls,
             ttl: rr.ttl,
             kind: RecordKind::from_rr_data(&rr.data),
+            cache_flush: rr.multicast_unique,
+            section,
         }
     }
+
+    /// Gets the identity of this record, which is the same for every copy of
+    /// it however long it has left to live.
+    pub fn key(&self) -> RecordKey {
+        RecordKey {
+            name: self.name.clone(),
+            class: self.class as u16,
+            kind: self.kind.clone(),
+        }
+    }
+
+    /// Gets the strings of a `TXT` record as raw bytes, in the order they
+    /// were sent.
+    ///
+    /// Returns `None` if this isn't a `TXT` record.
+    pub fn txt_raw(&self) -> Option<&[Vec<u8>]> {
+        match self.kind {
+            RecordKind::TXT(ref txt) => Some(txt),
+            _ => None,
+        }
+    }
+
+    /// Checks whether this record answers a question for `name` of type `query_type`.
+    pub(crate) fn answers(&self, name: &str, query_type: dns_parser::QueryType) -> bool {
+        use dns_parser::QueryType;
+
+        self.name == name
+            && match query_type {
+                QueryType::All => true,
+                query_type => self.kind.query_type() == Some(query_type),
+            }
+    }
+}
+
+// Leaves out the TTL and cache-flush bit, which is consistent with equality
+// as it only ever hashes fewer fields.
+impl Hash for Record {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        (self.class as u16).hash(state);
+        self.kind.hash(state);
+    }
 }
 
 impl RecordKind {
+    /// Gets the type of query this kind of record answers.
+    pub(crate) fn query_type(&self) -> Option<dns_parser::QueryType> {
+        use dns_parser::QueryType;
+
+        match *self {
+            RecordKind::A(..) => Some(QueryType::A),
+            RecordKind::AAAA(..) => Some(QueryType::AAAA),
+            RecordKind::CNAME(..) => Some(QueryType::CNAME),
+            RecordKind::MX { .. } => Some(QueryType::MX),
+            RecordKind::NS(..) => Some(QueryType::NS),
+            RecordKind::SRV { .. } => Some(QueryType::SRV),
+            RecordKind::TXT(..) => Some(QueryType::TXT),
+            RecordKind::PTR(..) => Some(QueryType::PTR),
+            RecordKind::Unimplemented(..) => None,
+        }
+    }
+
     fn from_rr_data(data: &dns_parser::RData) -> Self {
         use dns_parser::RData;
 
@@ -149,11 +444,7 @@ impl RecordKind {
                 port,
                 target: target.to_string(),
             },
-            RData::TXT(ref txt) => RecordKind::TXT(
-                txt.iter()
-                    .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
-                    .collect(),
-            ),
+            RData::TXT(ref txt) => RecordKind::TXT(txt.iter().map(<[u8]>::to_vec).collect()),
             RData::SOA(..) => {
                 RecordKind::Unimplemented("SOA record handling is not implemented".into())
             }
@@ -161,3 +452,127 @@ impl RecordKind {
         }
     }
 }
+
+#[cfg(feature = "serde")]
+fn serialize_class<S>(class: &dns_parser::Class, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_str(&format_args!("{:?}", class))
+}
+
+#[cfg(feature = "serde")]
+fn serialize_escaped_bytes<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let escaped: String = bytes
+        .iter()
+        .flat_map(|&byte| std::ascii::escape_default(byte))
+        .map(char::from)
+        .collect();
+
+    serializer.serialize_str(&escaped)
+}
+
+#[cfg(feature = "serde")]
+fn serialize_escaped_strings<S>(strings: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    use serde::ser::SerializeSeq;
+
+    struct Escaped<'a>(&'a [u8]);
+
+    impl serde::Serialize for Escaped<'_> {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: serde::Serializer,
+        {
+            serialize_escaped_bytes(self.0, serializer)
+        }
+    }
+
+    let mut seq = serializer.serialize_seq(Some(strings.len()))?;
+    for string in strings {
+        seq.serialize_element(&Escaped(string))?;
+    }
+    seq.end()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn srv(target: &str, priority: u16, weight: u16) -> Record {
+        Record {
+            name: "printer._http._tcp.local".to_owned(),
+            class: dns_parser::Class::IN,
+            ttl: 120,
+            kind: RecordKind::SRV {
+                priority,
+                weight,
+                port: 80,
+                target: target.to_owned(),
+            },
+            cache_flush: false,
+            section: Section::Answer,
+        }
+    }
+
+    fn response(answers: Vec<Record>) -> Response {
+        Response {
+            answers,
+            nameservers: Vec::new(),
+            additional: Vec::new(),
+            rtt: None,
+            local_addr: None,
+            interface_index: None,
+            peer_addr: None,
+        }
+    }
+
+    fn targets(response: &Response) -> Vec<String> {
+        response
+            .srv_targets_sorted()
+            .into_iter()
+            .map(|(target, _)| target)
+            .collect()
+    }
+
+    #[test]
+    fn srv_targets_sorted_puts_lower_priorities_first() {
+        let response = response(vec![
+            srv("c.local", 10, 50),
+            srv("a.local", 0, 0),
+            srv("b.local", 5, 100),
+        ]);
+
+        assert_eq!(targets(&response), ["a.local", "b.local", "c.local"]);
+    }
+
+    #[test]
+    fn srv_targets_sorted_keeps_every_target_of_a_priority() {
+        let response = response(vec![
+            srv("a.local", 0, 0),
+            srv("b.local", 0, 10),
+            srv("c.local", 0, 20),
+        ]);
+
+        let mut targets = targets(&response);
+        targets.sort();
+        assert_eq!(targets, ["a.local", "b.local", "c.local"]);
+    }
+
+    #[test]
+    fn srv_targets_sorted_favours_heavier_weights() {
+        fastrand::seed(7);
+        let response = response(vec![srv("light.local", 0, 100), srv("heavy.local", 0, 300)]);
+
+        let heavy_first = (0..1000)
+            .filter(|_| targets(&response)[0] == "heavy.local")
+            .count();
+        // The heavy target should come first about three times in four.
+        assert!((650..=850).contains(&heavy_first), "{}", heavy_first);
+    }
+}
@@ -0,0 +1,58 @@
+use crate::{DiscoveryCache, Error, Record, Response};
+
+use futures_util::future::ready;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+/// The future returned by the methods of a [`DiscoveryStore`].
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Somewhere discovered records are kept, such as a database shared between
+/// processes.
+///
+/// A store is kept up to date by [`Discovery::store`](crate::discover::Discovery::store).
+/// The methods return boxed futures so that stores can be used as trait
+/// objects. Backends with their own errors should report them as
+/// [`Error::Io`].
+///
+/// A `Mutex<DiscoveryCache>` is a store that keeps records in memory.
+pub trait DiscoveryStore: Send + Sync {
+    /// Adds every record in a response, with the same semantics as
+    /// [`DiscoveryCache::insert`].
+    fn insert<'a>(&'a self, response: &'a Response) -> StoreFuture<'a, Result<(), Error>>;
+
+    /// Gets the stored records of `name`, with the TTL they have left.
+    fn get<'a>(&'a self, name: &'a str) -> StoreFuture<'a, Result<Vec<Record>, Error>>;
+
+    /// Removes every stored record of `name`.
+    fn remove<'a>(&'a self, name: &'a str) -> StoreFuture<'a, Result<(), Error>>;
+
+    /// Removes all records whose TTL has lapsed.
+    fn expire(&self) -> StoreFuture<'_, Result<(), Error>>;
+}
+
+impl DiscoveryStore for Mutex<DiscoveryCache> {
+    fn insert<'a>(&'a self, response: &'a Response) -> StoreFuture<'a, Result<(), Error>> {
+        self.lock().unwrap().insert(response);
+        Box::pin(ready(Ok(())))
+    }
+
+    fn get<'a>(&'a self, name: &'a str) -> StoreFuture<'a, Result<Vec<Record>, Error>> {
+        let records = self
+            .lock()
+            .unwrap()
+            .answers(name, dns_parser::QueryType::All);
+        Box::pin(ready(Ok(records)))
+    }
+
+    fn remove<'a>(&'a self, name: &'a str) -> StoreFuture<'a, Result<(), Error>> {
+        self.lock().unwrap().remove(name);
+        Box::pin(ready(Ok(())))
+    }
+
+    fn expire(&self) -> StoreFuture<'_, Result<(), Error>> {
+        self.lock().unwrap().expire();
+        Box::pin(ready(Ok(())))
+    }
+}